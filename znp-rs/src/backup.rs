@@ -0,0 +1,485 @@
+//! Coordinator backup in the open coordinator backup format other Zigbee stacks read.
+//!
+//! The top level fields describe the network for other stacks. The raw NV items go
+//! under `stack_specific.zstack.nvram` and are what `restore` writes back, byte for byte.
+use crate::cmd::sys::{self, NvId};
+use crate::cmd::zb::ZbDeviceInfoProp;
+use crate::hex;
+use crate::init_coord::{device_info, ZNP_HAS_CONFIGURED};
+use crate::network;
+use crate::nv::{self, NvError};
+use crate::znp::{Sender, SreqError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::{fs, io, path::Path};
+
+pub const FORMAT: &str = "zigpy/open-coordinator-backup";
+pub const VERSION: u32 = 1;
+
+/// Single legacy items worth keeping
+const LEGACY_ITEMS: &[(u16, &str)] = &[
+    (0x0001, "EXTADDR"),
+    (0x0003, "STARTUP_OPTION"),
+    (0x0021, "NIB"),
+    (0x0022, "DEVICE_LIST"),
+    (0x0023, "ADDRMGR"),
+    (0x002D, "EXTENDED_PAN_ID"),
+    (0x003A, "NWK_ACTIVE_KEY_INFO"),
+    (0x003B, "NWK_ALTERN_KEY_INFO"),
+    (0x0062, "PRECFGKEY"),
+    (0x0063, "PRECFGKEYS_ENABLE"),
+    (0x0064, "SECURITY_MODE"),
+    (0x006D, "USE_DEFAULT_TCLK"),
+    (0x0082, "NWKKEY"),
+    (0x0083, "PANID"),
+    (0x0084, "CHANLIST"),
+    (0x0087, "LOGICAL_TYPE"),
+    (ZNP_HAS_CONFIGURED, "ZNP_HAS_CONFIGURED"),
+];
+const LEGACY_ADDRMGR: u16 = 0x0023;
+const LEGACY_NWK_ACTIVE_KEY_INFO: u16 = 0x003A;
+const LEGACY_NWKKEY: u16 = 0x0082;
+const LEGACY_CHANLIST: u16 = 0x0084;
+
+/// Legacy tables kept one entry per id, read until the first missing one
+const LEGACY_TABLES: &[(u16, u16)] = &[
+    // NWK_SEC_MATERIAL_TABLE
+    (0x0075, 0x0080),
+    // TCLK_TABLE, or TCLK_SEED and friends on 3.0
+    (0x0101, 0x01FF),
+    // APS_LINK_KEY_DATA_TABLE
+    (0x0201, 0x02FF),
+];
+const LEGACY_NWK_SEC_MATERIAL: u16 = 0x0075;
+
+/// Z-Stack 3.x tables under `NvId::SYS_ZSTACK`, one entry per sub id
+const ZSTACK_TABLES: &[(u16, &str)] = &[
+    (0x0001, "ADDRMGR"),
+    (0x0002, "BINDING_TABLE"),
+    (0x0003, "DEVICE_LIST"),
+    (0x0004, "TCLK_TABLE"),
+    (0x0005, "TCLK_IC_TABLE"),
+    (0x0006, "APS_KEY_DATA_TABLE"),
+    (0x0007, "NWK_SEC_MATERIAL_TABLE"),
+];
+const ZSTACK_ADDRMGR: u16 = 0x0001;
+const ZSTACK_NWK_SEC_MATERIAL: u16 = 0x0007;
+/// Upper bound on sub ids per table
+const ZSTACK_TABLE_MAX: u16 = 0x0400;
+
+/// Added to every restored NWK frame counter, so frames sent between the backup
+/// and the restore don't make devices drop ours as replays
+const FRAME_COUNTER_MARGIN: u32 = 2500;
+
+/// `AddrMgrEntry.user` bit for associated children
+const ADDRMGR_USER_ASSOC: u8 = 0x01;
+
+#[derive(Debug)]
+pub enum BackupError {
+    Sreq(SreqError),
+    Nv(NvError),
+    IO(io::Error),
+    Json(serde_json::Error),
+    /// Not a string of hex digit pairs
+    BadHex(String),
+    /// Not our format or version
+    Format(String),
+    /// Made by another stack, there are no raw NV items to restore
+    NoNvram,
+}
+impl From<SreqError> for BackupError {
+    fn from(err: SreqError) -> Self {
+        BackupError::Sreq(err)
+    }
+}
+impl From<NvError> for BackupError {
+    fn from(err: NvError) -> Self {
+        BackupError::Nv(err)
+    }
+}
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> Self {
+        BackupError::IO(err)
+    }
+}
+impl From<serde_json::Error> for BackupError {
+    fn from(err: serde_json::Error) -> Self {
+        BackupError::Json(err)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Backup {
+    pub metadata: Metadata,
+    #[serde(default)]
+    pub stack_specific: StackSpecific,
+    /// Big endian hex
+    pub coordinator_ieee: String,
+    /// Big endian hex
+    pub pan_id: String,
+    /// Big endian hex
+    pub extended_pan_id: String,
+    /// Not parsed out of the NIB, whose layout differs between releases
+    pub nwk_update_id: u8,
+    pub security_level: u8,
+    pub channel: u8,
+    pub channel_mask: Vec<u8>,
+    pub network_key: NetworkKey,
+    pub devices: Vec<Device>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Metadata {
+    pub format: String,
+    pub version: u32,
+    pub source: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct StackSpecific {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zstack: Option<ZStack>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct ZStack {
+    pub nvram: Nvram,
+}
+
+/// Raw items as hex, keyed by hex id
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Nvram {
+    /// OSAL items, `"0021"`
+    pub legacy: BTreeMap<String, String>,
+    /// Z-Stack 3.x items under `NvId::SYS_ZSTACK`, `"0001:0000"` for item and sub id
+    #[serde(default)]
+    pub zstack: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NetworkKey {
+    pub key: String,
+    pub sequence_number: u8,
+    pub frame_counter: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Device {
+    /// Big endian hex
+    pub nwk_address: Option<String>,
+    /// Big endian hex
+    pub ieee_address: String,
+    pub is_child: bool,
+}
+
+impl Backup {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BackupError> {
+        let backup: Backup = serde_json::from_slice(&fs::read(path)?)?;
+        if backup.metadata.format != FORMAT || backup.metadata.version != VERSION {
+            let found = format!("{} v{}", backup.metadata.format, backup.metadata.version);
+            return Err(BackupError::Format(found));
+        }
+        Ok(backup)
+    }
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), BackupError> {
+        // Holds the network key
+        network::write_private(path, &serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Reads the network parameters and every network-critical NV item
+pub async fn backup(znp: &Sender) -> Result<Backup, BackupError> {
+    let ieee = znp.sreq(sys::GetExtAddr).await?.ext_addr.0;
+    let pan_id = device_info(znp, ZbDeviceInfoProp::PanId).await? as u16;
+    let ext_pan_id = device_info(znp, ZbDeviceInfoProp::ExtPanId).await?;
+    let channel = device_info(znp, ZbDeviceInfoProp::Channel).await? as u8;
+
+    let mut legacy = BTreeMap::new();
+    for &(id, name) in LEGACY_ITEMS {
+        if let Some(value) = nv::read(znp, id).await? {
+            tracing::debug!(id, name, len = value.len(), "backed up");
+            legacy.insert(id, value);
+        }
+    }
+    for &(start, end) in LEGACY_TABLES {
+        for id in start..=end {
+            match nv::read(znp, id).await? {
+                Some(value) => legacy.insert(id, value),
+                None => break,
+            };
+        }
+    }
+
+    let mut zstack = BTreeMap::new();
+    if has_sys_nv(znp).await? {
+        for &(item_id, name) in ZSTACK_TABLES {
+            for sub_id in 0..ZSTACK_TABLE_MAX {
+                let id = zstack_id(item_id, sub_id);
+                match nv::read_id(znp, id).await? {
+                    Some(value) => zstack.insert((item_id, sub_id), value),
+                    None => break,
+                };
+            }
+            tracing::debug!(item_id, name, "backed up");
+        }
+    }
+
+    let (key, sequence_number) = match legacy.get(&LEGACY_NWK_ACTIVE_KEY_INFO) {
+        Some(info) if info.len() >= 17 => (&info[1..17], info[0]),
+        _ => (&[][..], 0),
+    };
+    let network_key = NetworkKey {
+        key: hex::encode(key),
+        sequence_number,
+        frame_counter: frame_counter(&legacy, &zstack, ext_pan_id),
+    };
+    let channel_mask = match legacy.get(&LEGACY_CHANLIST) {
+        Some(mask) if mask.len() == 4 => {
+            let mask = u32::from_le_bytes([mask[0], mask[1], mask[2], mask[3]]);
+            (11..=26).filter(|ch| mask & 1 << ch != 0).collect()
+        }
+        _ => vec![channel],
+    };
+    let devices = devices(&legacy, &zstack);
+
+    Ok(Backup {
+        metadata: Metadata {
+            format: FORMAT.to_owned(),
+            version: VERSION,
+            source: concat!(env!("CARGO_PKG_NAME"), "@", env!("CARGO_PKG_VERSION")).to_owned(),
+        },
+        stack_specific: StackSpecific {
+            zstack: Some(ZStack {
+                nvram: Nvram {
+                    legacy: legacy
+                        .iter()
+                        .map(|(id, value)| (format!("{:04x}", id), hex::encode(value)))
+                        .collect(),
+                    zstack: zstack
+                        .iter()
+                        .map(|((item, sub), value)| {
+                            (format!("{:04x}:{:04x}", item, sub), hex::encode(value))
+                        })
+                        .collect(),
+                },
+            }),
+        },
+        coordinator_ieee: format!("{:016x}", ieee),
+        pan_id: format!("{:04x}", pan_id),
+        extended_pan_id: format!("{:016x}", ext_pan_id),
+        nwk_update_id: 0,
+        security_level: 5,
+        channel,
+        channel_mask,
+        network_key,
+        devices,
+    })
+}
+
+/// Writes the raw NV items back. Reset the stick afterwards so it starts from them.
+pub async fn restore(znp: &Sender, backup: &Backup) -> Result<(), BackupError> {
+    let nvram = match &backup.stack_specific.zstack {
+        Some(zstack) => &zstack.nvram,
+        None => return Err(BackupError::NoNvram),
+    };
+    for (id, value) in &nvram.legacy {
+        let id = parse_id(id)?;
+        let mut value = from_hex(value)?;
+        match id {
+            LEGACY_NWKKEY if value.len() >= 21 => add_frame_counter_margin(&mut value[17..21]),
+            id if (LEGACY_NWK_SEC_MATERIAL..=LEGACY_NWK_SEC_MATERIAL + 11).contains(&id)
+                && value.len() >= 12 =>
+            {
+                add_frame_counter_margin(&mut value[..4])
+            }
+            _ => {}
+        }
+        nv::write(znp, id, &value).await?;
+    }
+    for (id, value) in &nvram.zstack {
+        let (item, sub) = match id.find(':') {
+            Some(colon) => (parse_id(&id[..colon])?, parse_id(&id[colon + 1..])?),
+            None => return Err(BackupError::BadHex(id.clone())),
+        };
+        let mut value = from_hex(value)?;
+        if item == ZSTACK_NWK_SEC_MATERIAL && value.len() >= 12 {
+            add_frame_counter_margin(&mut value[..4]);
+        }
+        nv::write_id(znp, zstack_id(item, sub), &value).await?;
+    }
+    Ok(())
+}
+
+/// Bumps a little-endian frame counter, found where `frame_counter` reads it
+fn add_frame_counter_margin(counter: &mut [u8]) {
+    let bumped = u32::from_le_bytes([counter[0], counter[1], counter[2], counter[3]])
+        .saturating_add(FRAME_COUNTER_MARGIN);
+    counter.copy_from_slice(&bumped.to_le_bytes());
+}
+
+/// Whether the firmware has the Z-Stack 3.x NV commands
+async fn has_sys_nv(znp: &Sender) -> Result<bool, SreqError> {
+    let id = zstack_id(ZSTACK_ADDRMGR, 0);
+    match znp.sreq(sys::SysNvLength { id }).await {
+        Ok(_) => Ok(true),
+        Err(SreqError::Rpc(_)) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn zstack_id(item_id: u16, sub_id: u16) -> NvId {
+    NvId {
+        sys_id: NvId::SYS_ZSTACK,
+        item_id,
+        sub_id,
+    }
+}
+
+/// Z-Stack 1.2 keeps it after the key in NWKKEY, later releases in the security
+/// material entry for the network's extended PAN id, or the catch-all one.
+fn frame_counter(
+    legacy: &BTreeMap<u16, Vec<u8>>,
+    zstack: &BTreeMap<(u16, u16), Vec<u8>>,
+    ext_pan_id: u64,
+) -> u32 {
+    if let Some(item) = legacy.get(&LEGACY_NWKKEY) {
+        if item.len() >= 21 {
+            return u32::from_le_bytes([item[17], item[18], item[19], item[20]]);
+        }
+    }
+    let entries = legacy
+        .range(LEGACY_NWK_SEC_MATERIAL..=LEGACY_NWK_SEC_MATERIAL + 11)
+        .map(|(_, entry)| entry)
+        .chain(
+            zstack
+                .range((ZSTACK_NWK_SEC_MATERIAL, 0)..(ZSTACK_NWK_SEC_MATERIAL + 1, 0))
+                .map(|(_, entry)| entry),
+        )
+        .filter(|entry| entry.len() >= 12);
+    let mut generic = None;
+    for entry in entries {
+        let counter = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+        if entry[4..12] == ext_pan_id.to_le_bytes() {
+            return counter;
+        }
+        if entry[4..12] == [0xFF; 8] {
+            generic = Some(counter);
+        }
+    }
+    generic.unwrap_or(0)
+}
+
+/// Address manager entries, packed to 11 bytes on the 8051 and aligned to 12 on ARM
+fn devices(legacy: &BTreeMap<u16, Vec<u8>>, zstack: &BTreeMap<(u16, u16), Vec<u8>>) -> Vec<Device> {
+    let table = legacy.get(&LEGACY_ADDRMGR);
+    let entries: Vec<&[u8]> = match table {
+        Some(table) if table.len() % 12 == 0 => table.chunks(12).collect(),
+        Some(table) if table.len() % 11 == 0 => table.chunks(11).collect(),
+        _ => zstack
+            .range((ZSTACK_ADDRMGR, 0)..(ZSTACK_ADDRMGR + 1, 0))
+            .map(|(_, entry)| &entry[..])
+            .collect(),
+    };
+    entries
+        .into_iter()
+        .filter_map(|entry| {
+            let (user, nwk, ext) = match entry.len() {
+                11 => (entry[0], &entry[1..3], &entry[3..11]),
+                12 => (entry[0], &entry[2..4], &entry[4..12]),
+                _ => return None,
+            };
+            if user == 0 || ext == [0x00; 8] || ext == [0xFF; 8] {
+                return None;
+            }
+            let nwk = u16::from_le_bytes([nwk[0], nwk[1]]);
+            let mut ieee = [0; 8];
+            ieee.copy_from_slice(ext);
+            Some(Device {
+                nwk_address: if nwk < 0xFFF8 {
+                    Some(format!("{:04x}", nwk))
+                } else {
+                    None
+                },
+                ieee_address: format!("{:016x}", u64::from_le_bytes(ieee)),
+                is_child: user & ADDRMGR_USER_ASSOC != 0,
+            })
+        })
+        .collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, BackupError> {
+    hex::decode(hex).ok_or_else(|| BackupError::BadHex(hex.to_owned()))
+}
+
+fn parse_id(hex: &str) -> Result<u16, BackupError> {
+    u16::from_str_radix(hex, 16).map_err(|_| BackupError::BadHex(hex.to_owned()))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::sim::Simulator;
+
+    #[tokio::test]
+    async fn restores_backup_onto_blank_stick() {
+        let mut sim = Simulator::new();
+        let ext_pan_id = sim.device_mut().ext_pan_id;
+        let nv = &mut sim.device_mut().nv;
+        let sec_material = |counter: u32, ext_pan_id: u64| {
+            let mut entry = counter.to_le_bytes().to_vec();
+            entry.extend_from_slice(&ext_pan_id.to_le_bytes());
+            entry
+        };
+        // Sequence number, then the key
+        let key_info: Vec<u8> = (0..17).collect();
+        nv.insert(NvId::legacy(LEGACY_NWK_ACTIVE_KEY_INFO), key_info);
+        nv.insert(NvId::legacy(0x0021), vec![0x5A; 116]);
+        nv.insert(
+            NvId::legacy(LEGACY_NWK_SEC_MATERIAL),
+            sec_material(7, 0xFFFF_FFFF_FFFF_FFFF),
+        );
+        nv.insert(
+            NvId::legacy(LEGACY_NWK_SEC_MATERIAL + 1),
+            sec_material(0x1234_5678, ext_pan_id),
+        );
+        let mut addrmgr = vec![ADDRMGR_USER_ASSOC, 0x00, 0x34, 0x12];
+        addrmgr.extend_from_slice(&0x0011_2233_4455_6677u64.to_le_bytes());
+        nv.insert(zstack_id(ZSTACK_ADDRMGR, 0), addrmgr);
+        let original = nv.clone();
+        // Tables end at the first missing entry
+        nv.insert(NvId::legacy(LEGACY_NWK_SEC_MATERIAL + 3), vec![0; 12]);
+
+        let (io, _handle) = sim.spawn().unwrap();
+        let backup = backup(&Sender::new(io)).await.unwrap();
+        assert_eq!(
+            backup.network_key.key,
+            hex::encode(&(1..17).collect::<Vec<u8>>())
+        );
+        assert_eq!(backup.network_key.sequence_number, 0);
+        assert_eq!(backup.network_key.frame_counter, 0x1234_5678);
+        assert_eq!(backup.devices.len(), 1);
+        assert_eq!(backup.devices[0].nwk_address.as_deref(), Some("1234"));
+        assert_eq!(backup.devices[0].ieee_address, "0011223344556677");
+        assert!(backup.devices[0].is_child);
+
+        // Through the file format
+        let backup: Backup = serde_json::from_slice(&serde_json::to_vec(&backup).unwrap()).unwrap();
+        let (io, blank) = Simulator::new().spawn().unwrap();
+        blank.device().nv.clear();
+        let znp = Sender::new(io);
+        restore(&znp, &backup).await.unwrap();
+        let restored = super::backup(&znp).await.unwrap();
+        assert!(restored.network_key.frame_counter > 0x1234_5678);
+        let mut expected = original;
+        for (id, counter) in &[
+            (LEGACY_NWK_SEC_MATERIAL, 7),
+            (LEGACY_NWK_SEC_MATERIAL + 1, 0x1234_5678),
+        ] {
+            let entry = expected.get_mut(&NvId::legacy(*id)).unwrap();
+            let bumped = counter + FRAME_COUNTER_MARGIN;
+            entry[..4].copy_from_slice(&bumped.to_le_bytes());
+        }
+        assert_eq!(blank.device().nv, expected);
+    }
+}
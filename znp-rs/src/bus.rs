@@ -0,0 +1,92 @@
+//! Fan-out of incoming AREQs to any number of subscribers, see `znp::Sender::subscribe`.
+//! Publishing never waits: a subscriber that falls behind loses the oldest AREQs
+//! and is told how many.
+use crate::cmd::types::ShortAddr;
+use crate::cmd::Areq;
+use crate::znp_codec::Subsys;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Which AREQs a subscriber gets, `None` fields match anything
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AreqFilter {
+    pub subsys: Option<Subsys>,
+    pub cmd_id: Option<u8>,
+    /// Source network address, see `cmd::Areq::sender`
+    pub sender: Option<ShortAddr>,
+    /// AF cluster, see `cmd::Areq::cluster`
+    pub cluster: Option<u16>,
+}
+impl AreqFilter {
+    pub fn matches(&self, areq: &Areq) -> bool {
+        fn field<T: PartialEq>(want: Option<T>, got: Option<T>) -> bool {
+            want.is_none() || want == got
+        }
+        field(self.subsys, Some(areq.subsys()))
+            && field(self.cmd_id, Some(areq.cmd_id()))
+            && field(self.sender, areq.sender())
+            && field(self.cluster, areq.cluster())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Areq(Arc<Areq>),
+    /// This many AREQs were dropped because the subscriber was too slow
+    Lagged(u64),
+}
+
+pub struct Subscription {
+    rx: broadcast::Receiver<Arc<Areq>>,
+    filter: AreqFilter,
+}
+impl Subscription {
+    /// `None` once the connection is gone for good, after `Status::Closed`
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            match self.rx.recv().await {
+                Ok(areq) if self.filter.matches(&areq) => return Some(Event::Areq(areq)),
+                Ok(_) => {}
+                Err(broadcast::RecvError::Lagged(count)) => return Some(Event::Lagged(count)),
+                Err(broadcast::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Clones share one channel, `close` ends it for all of them
+#[derive(Clone)]
+pub struct Bus {
+    tx: Arc<Mutex<Option<broadcast::Sender<Arc<Areq>>>>>,
+}
+impl Bus {
+    /// Each subscriber can be `capacity` AREQs behind before lagging
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Bus {
+            tx: Arc::new(Mutex::new(Some(tx))),
+        }
+    }
+    pub fn publish(&self, areq: Areq) {
+        if let Ok(tx) = self.tx.lock() {
+            if let Some(tx) = &*tx {
+                // Having no subscribers is fine
+                let _ = tx.send(Arc::new(areq));
+            }
+        }
+    }
+    pub fn subscribe(&self, filter: AreqFilter) -> Subscription {
+        let rx = match self.tx.lock().ok().as_ref().and_then(|tx| tx.as_ref()) {
+            Some(tx) => tx.subscribe(),
+            // Already closed, so is this one
+            None => broadcast::channel(1).1,
+        };
+        Subscription { rx, filter }
+    }
+    /// Every `Subscription::recv` returns `None` once it has caught up
+    pub fn close(&self) {
+        if let Ok(mut tx) = self.tx.lock() {
+            tx.take();
+        }
+    }
+}
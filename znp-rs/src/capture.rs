@@ -0,0 +1,217 @@
+//! Recording ZNP traffic as JSON lines, and replaying a recording as a fake coordinator
+#[cfg(unix)]
+use crate::znp_codec::ZnpCodec;
+use crate::znp_codec::{Subsys, Type, ZnpCmd};
+use bytes::BytesMut;
+#[cfg(unix)]
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+#[cfg(unix)]
+use tokio_util::codec::Framed;
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Direction {
+    /// Host to coordinator
+    Out,
+    /// Coordinator to host
+    In,
+}
+
+/// One frame, one line in the capture file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    pub direction: Direction,
+    /// Microseconds since the UNIX epoch
+    pub timestamp: u64,
+    #[serde(rename = "type")]
+    pub typ: Type,
+    pub subsys: Subsys,
+    pub cmd_id: u8,
+    /// Hex, so captures stay readable when attached to a ticket
+    #[serde(with = "crate::hex")]
+    pub body: Vec<u8>,
+}
+impl Record {
+    pub fn new(direction: Direction, cmd: &ZnpCmd) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_micros() as u64)
+            .unwrap_or(0);
+        Record {
+            direction,
+            timestamp,
+            typ: cmd.typ(),
+            subsys: cmd.subsys(),
+            cmd_id: cmd.cmd_id(),
+            body: cmd.body().to_vec(),
+        }
+    }
+    pub fn to_cmd(&self) -> ZnpCmd {
+        ZnpCmd::new(
+            self.typ,
+            self.subsys,
+            self.cmd_id,
+            BytesMut::from(&self.body[..]),
+        )
+    }
+    #[cfg(unix)]
+    fn matches(&self, cmd: &ZnpCmd) -> bool {
+        self.typ == cmd.typ() && self.subsys == cmd.subsys() && self.cmd_id == cmd.cmd_id()
+    }
+}
+
+/// Where a `Capture` puts its records
+pub trait RecordSink: Send {
+    fn write(&mut self, record: &Record) -> io::Result<()>;
+}
+/// The native format, one JSON `Record` per line
+pub struct JsonLines<W: Write + Send>(pub W);
+impl<W: Write + Send> RecordSink for JsonLines<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        serde_json::to_writer(&mut self.0, record)?;
+        self.0.write_all(b"\n")?;
+        // Flushed per line, so a crash still leaves a usable capture
+        self.0.flush()
+    }
+}
+
+/// Cloneable handle recording frames to a `RecordSink`
+#[derive(Clone)]
+pub struct Capture {
+    sink: Arc<Mutex<Box<dyn RecordSink>>>,
+}
+impl Capture {
+    /// JSON lines file, replayable with `replay`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(JsonLines(File::create(path)?)))
+    }
+    pub fn new<S: RecordSink + 'static>(sink: S) -> Self {
+        Capture {
+            sink: Arc::new(Mutex::new(Box::new(sink))),
+        }
+    }
+    /// Failing to record never fails the traffic being recorded
+    pub fn record(&self, direction: Direction, cmd: &ZnpCmd) {
+        let record = Record::new(direction, cmd);
+        let mut sink = match self.sink.lock() {
+            Ok(sink) => sink,
+            Err(_) => return,
+        };
+        if let Err(err) = sink.write(&record) {
+            warn!(error = %err, "capture write failed");
+        }
+    }
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<Record>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line)?);
+    }
+    Ok(records)
+}
+
+/// Fake coordinator answering with the recorded `In` frames.
+/// Each run of `In` records is sent once the host has sent the `Out` frame before it.
+/// Returns the host end of the pipe, for `znp::Sender::new`. Unix only.
+#[cfg(unix)]
+pub fn replay(records: Vec<Record>) -> io::Result<UnixStream> {
+    let (host, io) = UnixStream::pair()?;
+    tokio::spawn(run_replay(records, io));
+    Ok(host)
+}
+
+#[cfg(unix)]
+async fn run_replay(records: Vec<Record>, io: UnixStream) {
+    let (mut tx, mut rx) = Framed::new(io, ZnpCodec::new()).split();
+    for record in records {
+        match record.direction {
+            Direction::In => {
+                if tx.send(record.to_cmd()).await.is_err() {
+                    return;
+                }
+            }
+            Direction::Out => loop {
+                match rx.next().await {
+                    Some(Ok(cmd)) if record.matches(&cmd) => break,
+                    Some(Ok(cmd)) => warn!(?cmd, "replay: not in capture, ignoring"),
+                    Some(Err(_)) | None => return,
+                }
+            },
+        }
+    }
+    // Capture exhausted, stay connected until the host hangs up
+    while let Some(Ok(_)) = rx.next().await {}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::bus::{Event, Subscription};
+    use crate::cmd::types::{IEEEAddr, ShortAddr};
+    use crate::cmd::{sys, zdo, Areq};
+    use crate::sim::Simulator;
+    use crate::transport::Port;
+    use crate::znp::{Options, Sender};
+
+    async fn announced(sub: &mut Subscription) -> u16 {
+        match sub.recv().await {
+            Some(Event::Areq(areq)) => match &*areq {
+                Areq::Zdo(zdo::In::EndDevAnnce(annce)) => annce.nwk_addr.0,
+                areq => panic!("unexpected {:?}", areq),
+            },
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_recorded_session() {
+        let path = std::env::temp_dir().join(format!("znp-rs-replay-{}.jsonl", std::process::id()));
+        let mut sim = Simulator::new();
+        sim.device_mut().ieee_addr = IEEEAddr(0x0011_2233_4455_6677);
+        let (io, handle) = sim.spawn().unwrap();
+        let opts = Options {
+            capture: Some(Capture::create(&path).unwrap()),
+            ..Default::default()
+        };
+        let znp = Sender::with_options(io, opts);
+        let mut sub = znp.subscribe(Default::default());
+        znp.sreq(sys::GetExtAddr).await.unwrap();
+        for nwk_addr in &[0x1111, 0x2222] {
+            let annce = zdo::EndDevAnnce {
+                src_addr: ShortAddr(*nwk_addr),
+                nwk_addr: ShortAddr(*nwk_addr),
+                ieee_addr: IEEEAddr(0x0011_2233_4455_6677),
+                capabilities: 0x80,
+            };
+            handle.inject(&annce).unwrap();
+            assert_eq!(announced(&mut sub).await, *nwk_addr);
+        }
+        let capabilities = znp.sreq(sys::Ping).await.unwrap().capabilities;
+        drop(znp);
+
+        let port = Port::Replay(path.clone());
+        let znp = Sender::connect(&port, &Default::default()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut sub = znp.subscribe(Default::default());
+        let rsp = znp.sreq(sys::GetExtAddr).await.unwrap();
+        assert_eq!(rsp.ext_addr.0, 0x0011_2233_4455_6677);
+        assert_eq!(announced(&mut sub).await, 0x1111);
+        assert_eq!(announced(&mut sub).await, 0x2222);
+        let rsp = znp.sreq(sys::Ping).await.unwrap();
+        assert_eq!(rsp.capabilities, capabilities);
+    }
+}
@@ -30,6 +30,34 @@ impl Sreq for Register {
     const MAX_SIZE: usize = 0x49;
 }
 
+/// AF_DATA_REQUEST
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DataRequest {
+    /// Destination network address
+    pub dst_addr: ShortAddr,
+    pub dst_ep: u8,
+    pub src_ep: u8,
+    pub cluster: u16,
+    /// Transaction sequence number, echoed in AF_DATA_CONFIRM
+    pub trans_id: u8,
+    /// Bitmask: 0x10 APS ACK, 0x20 route discovery, 0x40 APS security, 0x80 skip routing
+    pub options: u8,
+    /// Maximum hops
+    pub radius: u8,
+    pub data: Vec<u8>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DataRequestRsp {
+    /// Success 0 or Failure 1
+    pub status: u8,
+}
+impl Sreq for DataRequest {
+    type Srsp = DataRequestRsp;
+    const SUBSYS: Subsys = Subsys::AF;
+    const CMD_ID: u8 = 0x01;
+    const MAX_SIZE: usize = 0x8A;
+}
+
 /// AF_INCOMING_MSG
 ///
 /// This callback message is in response to incoming data to any of the registered endpoints on this device.
@@ -65,4 +93,9 @@ impl In {
             _ => Err(Error::unimplemented(&cmd)),
         }
     }
+    pub fn cmd_id(&self) -> u8 {
+        match self {
+            In::IncomingMsg(_) => IncomingMsg::CMD_ID,
+        }
+    }
 }
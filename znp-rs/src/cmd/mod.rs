@@ -14,21 +14,52 @@ pub enum Areq {
     Sys(sys::In),
     Zdo(zdo::In),
     Af(af::In),
+    /// Not decoded yet, as it came off the wire
+    Raw(ZnpCmd),
 }
 impl Areq {
+    /// Unknown commands come back as `Raw`, only malformed ones fail
     pub fn from_subsys(cmd: ZnpCmd) -> Result<Self> {
         use Areq::*;
         use Subsys::*;
-        match cmd.subsys() {
-            SYS => Ok(Sys(sys::In::from_cmd(cmd)?)),
-            ZDO => Ok(Zdo(zdo::In::from_cmd(cmd)?)),
-            AF => Ok(Af(af::In::from_cmd(cmd)?)),
+        let raw = cmd.clone();
+        let areq = match cmd.subsys() {
+            SYS => sys::In::from_cmd(cmd).map(Sys),
+            ZDO => zdo::In::from_cmd(cmd).map(Zdo),
+            AF => af::In::from_cmd(cmd).map(Af),
             _ => Err(Error::unimplemented(&cmd)),
+        };
+        match areq {
+            Err(Error::Unimplemented { .. }) => Ok(Raw(raw)),
+            areq => areq,
+        }
+    }
+    pub fn subsys(&self) -> Subsys {
+        match self {
+            Areq::Sys(_) => Subsys::SYS,
+            Areq::Zdo(_) => Subsys::ZDO,
+            Areq::Af(_) => Subsys::AF,
+            Areq::Raw(cmd) => cmd.subsys(),
+        }
+    }
+    pub fn cmd_id(&self) -> u8 {
+        match self {
+            Areq::Sys(val) => val.cmd_id(),
+            Areq::Zdo(val) => val.cmd_id(),
+            Areq::Af(val) => val.cmd_id(),
+            Areq::Raw(cmd) => cmd.cmd_id(),
         }
     }
     pub fn sender(&self) -> Option<ShortAddr> {
         match self {
             Areq::Af(af::In::IncomingMsg(val)) => Some(val.addr),
+            Areq::Zdo(val) => val.sender(),
+            _ => None,
+        }
+    }
+    pub fn cluster(&self) -> Option<u16> {
+        match self {
+            Areq::Af(af::In::IncomingMsg(val)) => Some(val.cluster),
             _ => None,
         }
     }
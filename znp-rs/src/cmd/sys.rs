@@ -1,9 +1,294 @@
 use super::error::{Error, Result};
+use super::types::{self, IEEEAddr};
 use crate::areq::{AreqIn, AreqOut};
 use crate::sreq::Sreq;
 use crate::znp_codec::{Subsys, ZnpCmd};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::time::Duration;
+
+/// NV writes may have to wait for a flash page compaction, which takes seconds
+pub const NV_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// SYS_PING, answered by any running ZNP firmware
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Ping;
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingRsp {
+    /// Bitmask of the supported subsystems, see `supports`
+    pub capabilities: u16,
+}
+impl PingRsp {
+    /// MT_CAP_ZOAD, over the air download
+    pub const CAP_ZOAD: u16 = 0x1000;
+    /// Whether the firmware was built with `subsys`
+    pub fn supports(&self, subsys: Subsys) -> bool {
+        match subsys {
+            Subsys::Reserved => false,
+            // MT_CAP_SYS is bit 0, and so on in subsystem order
+            subsys => self.capabilities & (1 << (subsys as u16 - 1)) != 0,
+        }
+    }
+}
+impl Sreq for Ping {
+    type Srsp = PingRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x01;
+    const MAX_SIZE: usize = 0;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_VERSION
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Version;
+/// Z-Stack 3 appends a 4 byte build revision, which is ignored
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionRsp {
+    /// Transport protocol revision
+    pub transport_rev: u8,
+    pub product_id: u8,
+    pub major_rel: u8,
+    pub minor_rel: u8,
+    /// Maintenance release number
+    pub maint_rel: u8,
+}
+impl Sreq for Version {
+    type Srsp = VersionRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x02;
+    const MAX_SIZE: usize = 0;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_SET_EXTADDR, takes effect after a reset
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetExtAddr {
+    pub ext_addr: IEEEAddr,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetExtAddrRsp {
+    pub status: u8,
+}
+impl Sreq for SetExtAddr {
+    type Srsp = SetExtAddrRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x03;
+    const MAX_SIZE: usize = 8;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_GET_EXTADDR
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetExtAddr;
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetExtAddrRsp {
+    pub ext_addr: IEEEAddr,
+}
+impl Sreq for GetExtAddr {
+    type Srsp = GetExtAddrRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x04;
+    const MAX_SIZE: usize = 0;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_OSAL_STOP_TIMER
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopTimer {
+    /// 0-3
+    pub timer_id: u8,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopTimerRsp {
+    pub status: u8,
+}
+impl Sreq for StopTimer {
+    type Srsp = StopTimerRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x0B;
+    const MAX_SIZE: usize = 1;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_RANDOM
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Random;
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RandomRsp {
+    pub value: u16,
+}
+impl Sreq for Random {
+    type Srsp = RandomRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x0C;
+    const MAX_SIZE: usize = 0;
+}
+
+#[derive(Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum AdcChannel {
+    Ain0 = 0x00,
+    Ain1 = 0x01,
+    Ain2 = 0x02,
+    Ain3 = 0x03,
+    Ain4 = 0x04,
+    Ain5 = 0x05,
+    Ain6 = 0x06,
+    Ain7 = 0x07,
+    TemperatureSensor = 0x0E,
+    VoltageReading = 0x0F,
+}
+#[derive(Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum AdcResolution {
+    Bits8 = 0x00,
+    Bits10 = 0x01,
+    Bits12 = 0x02,
+    Bits14 = 0x03,
+}
+/// SYS_ADC_READ
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdcRead {
+    pub channel: AdcChannel,
+    pub resolution: AdcResolution,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdcReadRsp {
+    pub value: u16,
+}
+impl Sreq for AdcRead {
+    type Srsp = AdcReadRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x0D;
+    const MAX_SIZE: usize = 2;
+    const IDEMPOTENT: bool = true;
+}
+
+#[derive(Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum GpioOperation {
+    /// Bits of `value` set to 1 are outputs
+    SetDirection = 0x00,
+    /// Low nibble of `value` tristates, high nibble pulls down instead of up
+    SetInputMode = 0x01,
+    Set = 0x02,
+    Clear = 0x03,
+    Toggle = 0x04,
+    Read = 0x05,
+}
+/// SYS_GPIO, on the four application GPIOs
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Gpio {
+    pub operation: GpioOperation,
+    /// Bitmask of GPIO 0-3, or the mode for `SetInputMode`
+    pub value: u8,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GpioRsp {
+    /// Pin levels, after the operation
+    pub value: u8,
+}
+impl Sreq for Gpio {
+    type Srsp = GpioRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x0E;
+    const MAX_SIZE: usize = 2;
+}
+
+#[derive(Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum StackTuneOperation {
+    /// `value` in dBm
+    TxPower = 0x00,
+    /// `value` 1 keeps the receiver on while idle
+    RxOnIdle = 0x01,
+}
+/// SYS_STACK_TUNE
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StackTune {
+    pub operation: StackTuneOperation,
+    pub value: i8,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StackTuneRsp {
+    /// What the stack applied
+    pub value: i8,
+}
+impl Sreq for StackTune {
+    type Srsp = StackTuneRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x0F;
+    const MAX_SIZE: usize = 2;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_SET_TIME. A non-zero `utc_time` is used as is, otherwise the broken down fields.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetTime {
+    /// Seconds since 2000-01-01 00:00 UTC
+    pub utc_time: u32,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    /// 2000-
+    pub year: u16,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetTimeRsp {
+    pub status: u8,
+}
+impl Sreq for SetTime {
+    type Srsp = SetTimeRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x10;
+    const MAX_SIZE: usize = 11;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_GET_TIME
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetTime;
+/// Same fields as `SetTime`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetTimeRsp {
+    pub utc_time: u32,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub month: u8,
+    pub day: u8,
+    pub year: u16,
+}
+impl Sreq for GetTime {
+    type Srsp = GetTimeRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x11;
+    const MAX_SIZE: usize = 0;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_SET_TX_POWER
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetTxPower {
+    /// dBm
+    pub tx_power: i8,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetTxPowerRsp {
+    /// dBm actually set, the closest the radio supports
+    pub tx_power: i8,
+}
+impl Sreq for SetTxPower {
+    type Srsp = SetTxPowerRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x14;
+    const MAX_SIZE: usize = 1;
+    const IDEMPOTENT: bool = true;
+}
 
 /// SYS_OSAL_START_TIMER
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,7 +309,7 @@ impl Sreq for StartTimer {
     const MAX_SIZE: usize = 3;
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Debug)]
 #[repr(u8)]
 pub enum ResetReason {
     PowerUp = 0x00,
@@ -32,7 +317,7 @@ pub enum ResetReason {
     Watchdog = 0x02,
 }
 /// SYS_RESET_IND
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Reset {
     /// Reason for the reset
     pub reason: ResetReason,
@@ -61,6 +346,17 @@ impl AreqIn for TimerExpired {
     const CMD_ID: u8 = 0x81;
 }
 
+/// SYS_JAMMER_IND, with jammer detection compiled in
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JammerInd {
+    /// Whether jamming started or stopped
+    pub jammer_detected: bool,
+}
+impl AreqIn for JammerInd {
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x82;
+}
+
 /// SYS_OSAL_NV_READ
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NvRead {
@@ -69,20 +365,254 @@ pub struct NvRead {
     /// bytes offset from the beginning or the NV value
     pub offset: u8,
 }
+/// Also the answer to `NvReadExt` and `SysNvRead`
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NvReadRsp {
     /// Success 0 or Failure 1
-    status: u8,
-    value: Vec<u8>,
+    pub status: u8,
+    pub value: Vec<u8>,
 }
 impl Sreq for NvRead {
     type Srsp = NvReadRsp;
     const SUBSYS: Subsys = Subsys::SYS;
     const CMD_ID: u8 = 0x08;
     const MAX_SIZE: usize = 0xFA;
+    const IDEMPOTENT: bool = true;
+}
+
+/// Item exists but was never written, or was just created by `NvItemInit`
+pub const NV_ITEM_UNINIT: u8 = 0x09;
+pub const NV_OPER_FAILED: u8 = 0x0A;
+pub const NV_BAD_ITEM_LEN: u8 = 0x0C;
+
+/// Answer to the NV commands that only report success
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NvStatusRsp {
+    /// 0, or one of `NV_ITEM_UNINIT`, `NV_OPER_FAILED`, `NV_BAD_ITEM_LEN`
+    pub status: u8,
+}
+
+/// SYS_OSAL_NV_ITEM_INIT, creates the item unless it exists.
+/// Status 0 means it existed already, `NV_ITEM_UNINIT` that it was created.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NvItemInit {
+    pub id: u16,
+    pub item_len: u16,
+    /// Up to 245 bytes, the rest of the item is left erased
+    pub init_data: Vec<u8>,
+}
+impl Sreq for NvItemInit {
+    type Srsp = NvStatusRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x07;
+    const MAX_SIZE: usize = 0xFA;
+    const IDEMPOTENT: bool = true;
+    const TIMEOUT: Duration = NV_WRITE_TIMEOUT;
+}
+
+/// SYS_OSAL_NV_WRITE
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NvWrite {
+    pub id: u16,
+    pub offset: u8,
+    /// Up to 246 bytes
+    pub value: Vec<u8>,
+}
+impl Sreq for NvWrite {
+    type Srsp = NvStatusRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x09;
+    const MAX_SIZE: usize = 0xFA;
+    const IDEMPOTENT: bool = true;
+    const TIMEOUT: Duration = NV_WRITE_TIMEOUT;
+}
+
+/// SYS_OSAL_NV_DELETE, `item_len` must match the item
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NvDelete {
+    pub id: u16,
+    pub item_len: u16,
+}
+impl Sreq for NvDelete {
+    type Srsp = NvStatusRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x12;
+    const MAX_SIZE: usize = 4;
+    const IDEMPOTENT: bool = true;
+    const TIMEOUT: Duration = NV_WRITE_TIMEOUT;
+}
+
+/// SYS_OSAL_NV_LENGTH
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NvLength {
+    pub id: u16,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NvLengthRsp {
+    /// 0 if the item doesn't exist
+    pub length: u16,
+}
+impl Sreq for NvLength {
+    type Srsp = NvLengthRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x13;
+    const MAX_SIZE: usize = 2;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_OSAL_NV_READ_EXT, like `NvRead` but reaching past offset 255.
+/// Z-Stack 3.0 and later.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NvReadExt {
+    pub id: u16,
+    pub offset: u16,
+}
+impl Sreq for NvReadExt {
+    type Srsp = NvReadRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x1C;
+    const MAX_SIZE: usize = 4;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_OSAL_NV_WRITE_EXT, like `NvWrite` but reaching past offset 255.
+/// Z-Stack 3.0 and later.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NvWriteExt {
+    pub id: u16,
+    pub offset: u16,
+    /// Up to 244 bytes
+    #[serde(with = "types::u16_len")]
+    pub value: Vec<u8>,
+}
+impl Sreq for NvWriteExt {
+    type Srsp = NvStatusRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x1D;
+    const MAX_SIZE: usize = 0xFA;
+    const IDEMPOTENT: bool = true;
+    const TIMEOUT: Duration = NV_WRITE_TIMEOUT;
+}
+
+/// Z-Stack 3.x NV address, for the `SysNv*` commands.
+/// Legacy OSAL items are `NvId::legacy`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NvId {
+    pub sys_id: u8,
+    pub item_id: u16,
+    pub sub_id: u16,
+}
+impl NvId {
+    /// NVINTF_SYSID_ZSTACK
+    pub const SYS_ZSTACK: u8 = 0x01;
+    /// ZCD_NV_EX_LEGACY, where Z-Stack 3.x keeps the old flat items
+    pub const LEGACY: u16 = 0x0000;
+    pub fn legacy(id: u16) -> Self {
+        NvId {
+            sys_id: Self::SYS_ZSTACK,
+            item_id: Self::LEGACY,
+            sub_id: id,
+        }
+    }
+}
+
+/// SYS_NV_CREATE
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SysNvCreate {
+    pub id: NvId,
+    pub length: u32,
+}
+impl Sreq for SysNvCreate {
+    type Srsp = NvStatusRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x30;
+    const MAX_SIZE: usize = 9;
+    const IDEMPOTENT: bool = true;
+    const TIMEOUT: Duration = NV_WRITE_TIMEOUT;
+}
+
+/// SYS_NV_DELETE
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SysNvDelete {
+    pub id: NvId,
+}
+impl Sreq for SysNvDelete {
+    type Srsp = NvStatusRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x31;
+    const MAX_SIZE: usize = 5;
+    const IDEMPOTENT: bool = true;
+    const TIMEOUT: Duration = NV_WRITE_TIMEOUT;
+}
+
+/// SYS_NV_LENGTH
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SysNvLength {
+    pub id: NvId,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SysNvLengthRsp {
+    /// 0 if the item doesn't exist
+    pub length: u32,
+}
+impl Sreq for SysNvLength {
+    type Srsp = SysNvLengthRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x32;
+    const MAX_SIZE: usize = 5;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_NV_READ
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SysNvRead {
+    pub id: NvId,
+    pub offset: u16,
+    /// Up to 244 bytes
+    pub len: u8,
+}
+impl Sreq for SysNvRead {
+    type Srsp = NvReadRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x33;
+    const MAX_SIZE: usize = 8;
+    const IDEMPOTENT: bool = true;
+}
+
+/// SYS_NV_WRITE, into an existing item
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SysNvWrite {
+    pub id: NvId,
+    pub offset: u16,
+    /// Up to 242 bytes
+    pub value: Vec<u8>,
+}
+impl Sreq for SysNvWrite {
+    type Srsp = NvStatusRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x34;
+    const MAX_SIZE: usize = 0xFA;
+    const IDEMPOTENT: bool = true;
+    const TIMEOUT: Duration = NV_WRITE_TIMEOUT;
+}
+
+/// SYS_NV_UPDATE, replaces the whole item, creating it if needed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SysNvUpdate {
+    pub id: NvId,
+    /// Up to 244 bytes
+    pub value: Vec<u8>,
+}
+impl Sreq for SysNvUpdate {
+    type Srsp = NvStatusRsp;
+    const SUBSYS: Subsys = Subsys::SYS;
+    const CMD_ID: u8 = 0x35;
+    const MAX_SIZE: usize = 0xFA;
+    const IDEMPOTENT: bool = true;
+    const TIMEOUT: Duration = NV_WRITE_TIMEOUT;
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Debug)]
 #[repr(u8)]
 pub enum ResetType {
     /// Hardware/Watchdog reset
@@ -105,13 +635,22 @@ impl AreqOut for ResetReq {
 pub enum In {
     Reset(Reset),
     TimerExpired(TimerExpired),
+    Jammer(JammerInd),
 }
 impl In {
     pub fn from_cmd(cmd: ZnpCmd) -> Result<Self> {
         match cmd.cmd_id() {
             Reset::CMD_ID => Ok(In::Reset(cmd.parse()?)),
             TimerExpired::CMD_ID => Ok(In::TimerExpired(cmd.parse()?)),
+            JammerInd::CMD_ID => Ok(In::Jammer(cmd.parse()?)),
             _ => Err(Error::unimplemented(&cmd)),
         }
     }
+    pub fn cmd_id(&self) -> u8 {
+        match self {
+            In::Reset(_) => Reset::CMD_ID,
+            In::TimerExpired(_) => TimerExpired::CMD_ID,
+            In::Jammer(_) => JammerInd::CMD_ID,
+        }
+    }
 }
@@ -1,10 +1,49 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct ShortAddr(pub u16);
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub struct IEEEAddr(pub u64);
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Endpoint(pub u8);
+
+/// `Vec<u8>` with a 16 bit length prefix instead of the usual 8 bit one,
+/// for `#[serde(with = "types::u16_len")]`
+pub mod u16_len {
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::ser::SerializeTuple;
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], ser: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = ser.serialize_tuple(bytes.len() + 1)?;
+        tuple.serialize_element(&(bytes.len() as u16))?;
+        for byte in bytes {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Vec<u8>, D::Error> {
+        struct Prefixed;
+        impl<'de> Visitor<'de> for Prefixed {
+            type Value = Vec<u8>;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("u16 length and that many bytes")
+            }
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let len: u16 = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::custom("missing length"))?;
+                (0..len)
+                    .map(|_| {
+                        seq.next_element()?
+                            .ok_or_else(|| de::Error::custom("too few bytes"))
+                    })
+                    .collect()
+            }
+        }
+        de.deserialize_tuple(usize::from(u16::MAX) + 1, Prefixed)
+    }
+}
@@ -17,4 +17,5 @@ impl Sreq for UtilLedControl {
     const SUBSYS: Subsys = Subsys::UTIL;
     const CMD_ID: u8 = 0x0A;
     const MAX_SIZE: usize = 2;
+    const IDEMPOTENT: bool = true;
 }
@@ -29,9 +29,10 @@ impl Sreq for ZbGetDeviceInfoReq {
     const SUBSYS: Subsys = Subsys::SAPI;
     const CMD_ID: u8 = 0x06;
     const MAX_SIZE: usize = 9;
+    const IDEMPOTENT: bool = true;
 }
 
-#[derive(Copy, Clone, Serialize_repr, Deserialize_repr, Debug)]
+#[derive(Copy, Clone, Serialize_repr, Deserialize_repr, PartialEq, Eq, Hash, Debug)]
 #[repr(u8)]
 pub enum ConfigId {
     ///EXTADDR
@@ -255,4 +256,24 @@ impl Sreq for ReadConfig {
     const SUBSYS: Subsys = Subsys::SAPI;
     const CMD_ID: u8 = 0x04;
     const MAX_SIZE: usize = 0x83;
+    const IDEMPOTENT: bool = true;
+}
+
+/// ZB_WRITE_CONFIGURATION, takes effect after a reset
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WriteConfig {
+    pub id: ConfigId,
+    pub value: Vec<u8>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WriteConfigRsp {
+    /// Success 0 or Failure 1
+    pub status: u8,
+}
+impl Sreq for WriteConfig {
+    type Srsp = WriteConfigRsp;
+    const SUBSYS: Subsys = Subsys::SAPI;
+    const CMD_ID: u8 = 0x05;
+    const MAX_SIZE: usize = 0x82;
+    const IDEMPOTENT: bool = true;
 }
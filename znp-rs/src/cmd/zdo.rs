@@ -1,8 +1,9 @@
 use super::error::{Error, Result};
 use super::types::{Endpoint, IEEEAddr, ShortAddr};
 use crate::areq::AreqIn;
-use crate::sreq::Sreq;
+use crate::sreq::{Sreq, WithCallback};
 use crate::znp_codec::{Subsys, ZnpCmd};
+use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
@@ -24,6 +25,7 @@ impl Sreq for NodeDescReq {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x02;
     const MAX_SIZE: usize = 0x04;
+    const IDEMPOTENT: bool = true;
 }
 
 // #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
@@ -57,6 +59,15 @@ impl AreqIn for NodeDescRsp {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x82;
 }
+impl WithCallback for NodeDescReq {
+    type Callback = NodeDescRsp;
+    fn status(srsp: &NodeDescReqRsp) -> u8 {
+        srsp.status
+    }
+    fn matches(&self, rsp: &NodeDescRsp) -> bool {
+        rsp.src_addr == self.dest_addr && rsp.query_addr == self.query_addr
+    }
+}
 
 /// ZDO_POWER_DESC_REQ
 #[derive(Serialize, Deserialize, Debug)]
@@ -76,6 +87,7 @@ impl Sreq for PowerDescReq {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x03;
     const MAX_SIZE: usize = 0x04;
+    const IDEMPOTENT: bool = true;
 }
 
 /// ZDO_POWER_DESC_RSP
@@ -94,6 +106,15 @@ impl AreqIn for PowerDescRsp {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x83;
 }
+impl WithCallback for PowerDescReq {
+    type Callback = PowerDescRsp;
+    fn status(srsp: &PowerDescReqRsp) -> u8 {
+        srsp.status
+    }
+    fn matches(&self, rsp: &PowerDescRsp) -> bool {
+        rsp.src_addr == self.dest_addr && rsp.query_addr == self.query_addr
+    }
+}
 
 /// ZDO_SIMPLE_DESC_REQ
 #[derive(Serialize, Deserialize, Debug)]
@@ -115,6 +136,7 @@ impl Sreq for SimpleDescReq {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x04;
     const MAX_SIZE: usize = 0x04;
+    const IDEMPOTENT: bool = true;
 }
 
 /// ZDO_SIMPLE_DESC_RSP
@@ -140,6 +162,17 @@ impl AreqIn for SimpleDescRsp {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x84;
 }
+impl WithCallback for SimpleDescReq {
+    type Callback = SimpleDescRsp;
+    fn status(srsp: &SimpleDescReqRsp) -> u8 {
+        srsp.status
+    }
+    fn matches(&self, rsp: &SimpleDescRsp) -> bool {
+        rsp.src_addr == self.dest_addr
+            && rsp.query_addr == self.query_addr
+            && rsp.endpoint == self.endpoint
+    }
+}
 
 /// ZDO_ACTIVE_EP_REQ
 #[derive(Serialize, Deserialize, Debug)]
@@ -159,6 +192,7 @@ impl Sreq for ActiveEpReq {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x05;
     const MAX_SIZE: usize = 0x04;
+    const IDEMPOTENT: bool = true;
 }
 
 /// ZDO_ACTIVE_EP_RSP
@@ -174,6 +208,15 @@ impl AreqIn for ActiveEpRsp {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x85;
 }
+impl WithCallback for ActiveEpReq {
+    type Callback = ActiveEpRsp;
+    fn status(srsp: &ActiveEpReqRsp) -> u8 {
+        srsp.status
+    }
+    fn matches(&self, rsp: &ActiveEpRsp) -> bool {
+        rsp.src_addr == self.dest_addr && rsp.query_addr == self.query_addr
+    }
+}
 
 /// ZDO_COMPLEX_DESC_REQ
 #[derive(Serialize, Deserialize, Debug)]
@@ -193,6 +236,7 @@ impl Sreq for ComplexDescReq {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x07;
     const MAX_SIZE: usize = 0x04;
+    const IDEMPOTENT: bool = true;
 }
 
 /// ZDO_COMPLEX_DESC_RSP
@@ -209,6 +253,15 @@ impl AreqIn for ComplexDescRsp {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x87;
 }
+impl WithCallback for ComplexDescReq {
+    type Callback = ComplexDescRsp;
+    fn status(srsp: &ComplexDescReqRsp) -> u8 {
+        srsp.status
+    }
+    fn matches(&self, rsp: &ComplexDescRsp) -> bool {
+        rsp.src_addr == self.dest_addr && rsp.query_addr == self.query_addr
+    }
+}
 
 ///ZDO_MGMT_PERMIT_JOIN_REQ
 #[derive(Serialize, Deserialize, Debug)]
@@ -231,6 +284,7 @@ impl Sreq for MgmtPermitJoinReq {
     const SUBSYS: Subsys = Subsys::ZDO;
     const CMD_ID: u8 = 0x36;
     const MAX_SIZE: usize = 0x05;
+    const IDEMPOTENT: bool = true;
 }
 
 /// ZDO_MGMT_PERMIT_JOIN_RSP
@@ -266,7 +320,7 @@ pub enum StartupFromAppStatus {
     /// Leave and not Started
     Leave = 0x02,
 }
-/// ZDO_STARTUP_FROM_APP
+/// ZDO_STARTUP_FROM_APP, answered at once; ZDO_STATE_CHANGE_IND reports the network coming up
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StartupFromApp {
     /// StartDelay: time before device starts
@@ -283,10 +337,43 @@ impl Sreq for StartupFromApp {
     const MAX_SIZE: usize = 1;
 }
 
+/// devStates_t, as ZDO_STATE_CHANGE_IND and ZB_GET_DEVICE_INFO report it
+#[derive(Serialize_repr, Deserialize_repr, FromPrimitive, Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum DeviceState {
+    /// Initialized, not started automatically
+    Hold = 0x00,
+    /// Initialized, not connected to anything
+    Init = 0x01,
+    /// Discovering PANs to join
+    NwkDisc = 0x02,
+    NwkJoining = 0x03,
+    /// Rejoining, only for end devices
+    NwkRejoin = 0x04,
+    /// Joined but not yet authenticated by the trust center
+    EndDeviceUnauth = 0x05,
+    EndDevice = 0x06,
+    Router = 0x07,
+    /// Starting as coordinator
+    CoordStarting = 0x08,
+    /// Started as coordinator, DEV_ZB_COORD
+    ZbCoord = 0x09,
+    /// Lost its parent
+    NwkOrphan = 0x0A,
+    /// Sending a keep alive to its parent
+    NwkKa = 0x0B,
+    /// Waiting before trying to rejoin
+    NwkBackoff = 0x0C,
+    NwkSecRejoinCurrChannel = 0x0D,
+    NwkSecRejoinAllChannel = 0x0E,
+    NwkTcRejoinCurrChannel = 0x0F,
+    NwkTcRejoinAllChannel = 0x10,
+}
+
 /// ZDO_STATE_CHANGE_IND
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StateChange {
-    pub state: u8,
+    pub state: DeviceState,
 }
 impl AreqIn for StateChange {
     const SUBSYS: Subsys = Subsys::ZDO;
@@ -388,4 +475,35 @@ impl In {
             _ => Err(Error::unimplemented(&cmd)),
         }
     }
+    pub fn cmd_id(&self) -> u8 {
+        match self {
+            In::MgmtPermitJoinRsp(_) => MgmtPermitJoinRsp::CMD_ID,
+            In::MgmtPermitJoinInd(_) => MgmtPermitJoinInd::CMD_ID,
+            In::NodeDescRsp(_) => NodeDescRsp::CMD_ID,
+            In::PowerDescRsp(_) => PowerDescRsp::CMD_ID,
+            In::SimpleDescRsp(_) => SimpleDescRsp::CMD_ID,
+            In::ActiveEpRsp(_) => ActiveEpRsp::CMD_ID,
+            In::ComplexDescRsp(_) => ComplexDescRsp::CMD_ID,
+            In::StateChange(_) => StateChange::CMD_ID,
+            In::SourceRoute(_) => SourceRoute::CMD_ID,
+            In::EndDevAnnce(_) => EndDevAnnce::CMD_ID,
+            In::Leaving(_) => Leaving::CMD_ID,
+            In::TrustCntDev(_) => TrustCntDev::CMD_ID,
+        }
+    }
+    /// Network address the message came from, if it names one
+    pub fn sender(&self) -> Option<ShortAddr> {
+        match self {
+            In::MgmtPermitJoinRsp(val) => Some(val.src_addr),
+            In::NodeDescRsp(val) => Some(val.src_addr),
+            In::PowerDescRsp(val) => Some(val.src_addr),
+            In::SimpleDescRsp(val) => Some(val.src_addr),
+            In::ActiveEpRsp(val) => Some(val.src_addr),
+            In::ComplexDescRsp(val) => Some(val.src_addr),
+            In::EndDevAnnce(val) => Some(val.src_addr),
+            In::Leaving(val) => Some(val.addr),
+            In::TrustCntDev(val) => Some(val.addr),
+            In::MgmtPermitJoinInd(_) | In::StateChange(_) | In::SourceRoute(_) => None,
+        }
+    }
 }
@@ -0,0 +1,683 @@
+//! Firmware flashing through the CC2538/CC26xx ROM serial bootloader.
+//!
+//! The bootloader only listens when the chip booted with the bootloader pin held,
+//! or with no valid image in flash. Images are Intel HEX or raw binaries.
+//! `simulate` is a fake bootloader with flash in memory, on Unix.
+use crate::transport::Transport;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+#[cfg(unix)]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+use tracing::{debug, info};
+
+const ACK: u8 = 0xCC;
+const NACK: u8 = 0x33;
+const SYNC: [u8; 2] = [0x55, 0x55];
+/// Bootloader packets carry at most 255 bytes including size and checksum
+const MAX_DATA: usize = 252;
+
+const COMMAND_PING: u8 = 0x20;
+const COMMAND_DOWNLOAD: u8 = 0x21;
+const COMMAND_GET_STATUS: u8 = 0x23;
+const COMMAND_SEND_DATA: u8 = 0x24;
+const COMMAND_RESET: u8 = 0x25;
+/// Address range on CC2538, single sector on CC26xx
+const COMMAND_ERASE: u8 = 0x26;
+const COMMAND_CRC32: u8 = 0x27;
+const COMMAND_GET_CHIP_ID: u8 = 0x28;
+
+const COMMAND_RET_SUCCESS: u8 = 0x40;
+const COMMAND_RET_UNKNOWN_CMD: u8 = 0x41;
+const COMMAND_RET_INVALID_ADR: u8 = 0x43;
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    /// Intel HEX line number and what is wrong with it
+    Hex(usize, &'static str),
+    TimedOut,
+    /// The bootloader rejected a packet
+    Nack,
+    /// Neither ACK nor NACK, or a response with a bad checksum
+    Garbled,
+    /// COMMAND_GET_STATUS said something other than COMMAND_RET_SUCCESS
+    Status(u8),
+    /// Flash doesn't read back as written
+    Verify {
+        address: u32,
+        expected: u32,
+        actual: u32,
+    },
+    /// The image doesn't fit the chip's flash
+    OutOfRange(u32),
+}
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IO(err)
+    }
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chip {
+    /// 512 KiB at 0x0020_0000, erased by address range
+    Cc2538,
+    /// CC26x2/CC13x2, 352 KiB at 0, erased by 8 KiB sector
+    Cc26xx,
+}
+impl Chip {
+    pub fn flash_base(self) -> u32 {
+        match self {
+            Chip::Cc2538 => 0x0020_0000,
+            Chip::Cc26xx => 0x0000_0000,
+        }
+    }
+    pub fn flash_size(self) -> u32 {
+        match self {
+            Chip::Cc2538 => 512 * 1024,
+            Chip::Cc26xx => 352 * 1024,
+        }
+    }
+    /// Smallest erasable unit
+    pub fn page_size(self) -> u32 {
+        match self {
+            Chip::Cc2538 => 2048,
+            Chip::Cc26xx => 8192,
+        }
+    }
+    fn contains(self, address: u32, len: u32) -> bool {
+        let base = u64::from(self.flash_base());
+        let end = base + u64::from(self.flash_size());
+        u64::from(address) >= base && u64::from(address) + u64::from(len) <= end
+    }
+}
+
+/// Contiguous run of bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+impl Segment {
+    fn end(&self) -> u64 {
+        u64::from(self.address) + self.data.len() as u64
+    }
+}
+
+/// Firmware image, segments sorted by address and not overlapping
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+}
+impl Image {
+    /// `.hex` files are Intel HEX, anything else a raw binary flashed at `base`
+    pub fn load<P: AsRef<Path>>(path: P, base: u32) -> Result<Self> {
+        let path = path.as_ref();
+        let is_hex = path
+            .extension()
+            .map_or(false, |ext| ext.eq_ignore_ascii_case("hex"));
+        if is_hex {
+            Self::from_ihex(&std::fs::read_to_string(path)?)
+        } else {
+            Ok(Self::from_binary(base, std::fs::read(path)?))
+        }
+    }
+    pub fn from_binary(base: u32, data: Vec<u8>) -> Self {
+        Image {
+            segments: vec![Segment {
+                address: base,
+                data,
+            }],
+        }
+    }
+    /// Data, EOF, extended segment and extended linear address records.
+    /// Start address records are ignored, the ROM bootloader jumps to the vector table.
+    pub fn from_ihex(text: &str) -> Result<Self> {
+        let mut image = Image::default();
+        let mut upper = 0u32;
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let hex = line
+                .strip_prefix(':')
+                .ok_or(Error::Hex(line_no, "no start code"))?;
+            if !hex.is_ascii() || hex.len() % 2 != 0 || hex.len() < 10 {
+                return Err(Error::Hex(line_no, "too short or not hex"));
+            }
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<std::result::Result<Vec<u8>, _>>()
+                .map_err(|_| Error::Hex(line_no, "not hex"))?;
+            if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+                return Err(Error::Hex(line_no, "bad checksum"));
+            }
+            let len = bytes[0] as usize;
+            if bytes.len() != len + 5 {
+                return Err(Error::Hex(line_no, "length doesn't match"));
+            }
+            let offset = u32::from(BigEndian::read_u16(&bytes[1..3]));
+            let data = &bytes[4..4 + len];
+            match bytes[3] {
+                0x00 => image.insert(upper + offset, data),
+                0x01 => break,
+                0x02 if len == 2 => upper = u32::from(BigEndian::read_u16(data)) << 4,
+                0x04 if len == 2 => upper = u32::from(BigEndian::read_u16(data)) << 16,
+                0x03 | 0x05 => {}
+                _ => return Err(Error::Hex(line_no, "unknown record")),
+            }
+        }
+        Ok(image)
+    }
+    /// Later data overwrites earlier data at the same address
+    fn insert(&mut self, address: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        // Extending the last segment is by far the common case
+        if let Some(last) = self.segments.last_mut() {
+            if last.end() == u64::from(address) {
+                last.data.extend_from_slice(data);
+                return;
+            }
+        }
+        let mut merged = Segment {
+            address,
+            data: data.to_vec(),
+        };
+        let mut rest = Vec::with_capacity(self.segments.len() + 1);
+        for seg in self.segments.drain(..) {
+            if seg.end() < u64::from(merged.address) || u64::from(seg.address) > merged.end() {
+                rest.push(seg);
+                continue;
+            }
+            // Touching or overlapping, `merged` wins where both have data
+            let start = seg.address.min(merged.address);
+            let stop = seg.end().max(merged.end());
+            let mut data = vec![0xFF; (stop - u64::from(start)) as usize];
+            let at = (seg.address - start) as usize;
+            data[at..at + seg.data.len()].copy_from_slice(&seg.data);
+            let at = (merged.address - start) as usize;
+            data[at..at + merged.data.len()].copy_from_slice(&merged.data);
+            merged = Segment {
+                address: start,
+                data,
+            };
+        }
+        rest.push(merged);
+        rest.sort_by_key(|seg| seg.address);
+        self.segments = rest;
+    }
+    /// Segments padded with 0xFF to word boundaries, as COMMAND_DOWNLOAD wants them.
+    /// Segments sharing a word are merged first, so padding only ever fills gaps.
+    fn word_aligned(&self) -> Image {
+        let mut segments: Vec<Segment> = Vec::with_capacity(self.segments.len());
+        for seg in &self.segments {
+            let start = seg.address & !3;
+            match segments.last_mut() {
+                // Sorted and not overlapping, so only the gap between them is padding
+                Some(last) if last.end() > u64::from(start) => {
+                    let gap = (u64::from(seg.address) - last.end()) as usize;
+                    last.data.resize(last.data.len() + gap, 0xFF);
+                    last.data.extend_from_slice(&seg.data);
+                }
+                _ => {
+                    let mut data = vec![0xFF; (seg.address - start) as usize];
+                    data.extend_from_slice(&seg.data);
+                    segments.push(Segment {
+                        address: start,
+                        data,
+                    });
+                }
+            }
+        }
+        for seg in &mut segments {
+            seg.data.resize((seg.data.len() + 3) / 4 * 4, 0xFF);
+        }
+        Image { segments }
+    }
+}
+
+/// CRC-32/ISO-HDLC, what COMMAND_CRC32 computes
+fn crc32(buf: &[u8]) -> u32 {
+    !buf.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// One conversation with the ROM bootloader, over any `Transport`
+pub struct Bootloader<T> {
+    io: T,
+    chip: Chip,
+    /// For each ACK and response, erasing a large range can take a while
+    pub timeout: Duration,
+}
+impl<T: Transport> Bootloader<T> {
+    pub fn new(io: T, chip: Chip) -> Self {
+        Bootloader {
+            io,
+            chip,
+            timeout: Duration::from_secs(3),
+        }
+    }
+    /// Lets the bootloader detect the baud rate, must come first
+    pub async fn sync(&mut self) -> Result<()> {
+        self.io.write_all(&SYNC).await?;
+        self.ack().await
+    }
+    pub async fn ping(&mut self) -> Result<()> {
+        self.command(COMMAND_PING, &[]).await
+    }
+    pub async fn chip_id(&mut self) -> Result<u32> {
+        self.command(COMMAND_GET_CHIP_ID, &[]).await?;
+        let rsp = self.response().await?;
+        if rsp.len() != 4 {
+            return Err(Error::Garbled);
+        }
+        Ok(BigEndian::read_u32(&rsp))
+    }
+    /// Whole pages covering `address..address + size`
+    pub async fn erase(&mut self, address: u32, size: u32) -> Result<()> {
+        let page = self.chip.page_size();
+        let first = address / page;
+        let end = u64::from(address) + u64::from(size);
+        let last = (end + u64::from(page) - 1) / u64::from(page);
+        match self.chip {
+            Chip::Cc2538 => {
+                let size = (last as u32 - first) * page;
+                self.command_checked(COMMAND_ERASE, &addr_size(first * page, size))
+                    .await
+            }
+            Chip::Cc26xx => {
+                for sector in first..last as u32 {
+                    let mut args = [0; 4];
+                    BigEndian::write_u32(&mut args, sector * page);
+                    self.command_checked(COMMAND_ERASE, &args).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+    /// Announces `size` bytes of `send_data` for `address`, both word aligned
+    pub async fn download(&mut self, address: u32, size: u32) -> Result<()> {
+        self.command_checked(COMMAND_DOWNLOAD, &addr_size(address, size))
+            .await
+    }
+    /// At most 252 bytes per call
+    pub async fn send_data(&mut self, data: &[u8]) -> Result<()> {
+        self.command_checked(COMMAND_SEND_DATA, data).await
+    }
+    pub async fn crc32(&mut self, address: u32, size: u32) -> Result<u32> {
+        let mut args = addr_size(address, size).to_vec();
+        if self.chip == Chip::Cc26xx {
+            // Read repeat count
+            args.extend_from_slice(&[0; 4]);
+        }
+        self.command(COMMAND_CRC32, &args).await?;
+        let rsp = self.response().await?;
+        if rsp.len() != 4 {
+            return Err(Error::Garbled);
+        }
+        Ok(BigEndian::read_u32(&rsp))
+    }
+    /// Boots the new image, the bootloader is gone afterwards
+    pub async fn reset(mut self) -> Result<()> {
+        self.command(COMMAND_RESET, &[]).await
+    }
+    /// Erase, write and verify every segment of `image`, then reset
+    pub async fn flash(mut self, image: &Image) -> Result<()> {
+        let image = image.word_aligned();
+        for seg in &image.segments {
+            if !self.chip.contains(seg.address, seg.data.len() as u32) {
+                return Err(Error::OutOfRange(seg.address));
+            }
+        }
+        self.sync().await?;
+        self.ping().await?;
+        // All erasing first, neighbouring segments can share a page
+        let page = self.chip.page_size();
+        let pages = image
+            .segments
+            .iter()
+            .flat_map(|seg| seg.address / page..=(seg.end() as u32 - 1) / page)
+            .collect::<BTreeSet<_>>();
+        info!(pages = pages.len(), "erasing");
+        for page_no in pages {
+            self.erase(page_no * page, page).await?;
+        }
+        for seg in &image.segments {
+            let size = seg.data.len() as u32;
+            info!(address = seg.address, size, "writing");
+            self.download(seg.address, size).await?;
+            for chunk in seg.data.chunks(MAX_DATA) {
+                self.send_data(chunk).await?;
+            }
+            let expected = crc32(&seg.data);
+            let actual = self.crc32(seg.address, size).await?;
+            if actual != expected {
+                return Err(Error::Verify {
+                    address: seg.address,
+                    expected,
+                    actual,
+                });
+            }
+            debug!(address = seg.address, crc = actual, "verified");
+        }
+        info!("flashed, resetting");
+        self.reset().await
+    }
+
+    async fn command(&mut self, cmd: u8, args: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(args.len() + 3);
+        packet.push(args.len() as u8 + 3);
+        packet.push(cmd.wrapping_add(checksum(args)));
+        packet.push(cmd);
+        packet.extend_from_slice(args);
+        self.io.write_all(&packet).await?;
+        self.ack().await
+    }
+    /// Follows up with COMMAND_GET_STATUS, for commands that can fail after the ACK
+    async fn command_checked(&mut self, cmd: u8, args: &[u8]) -> Result<()> {
+        self.command(cmd, args).await?;
+        self.command(COMMAND_GET_STATUS, &[]).await?;
+        match self.response().await?.as_slice() {
+            [COMMAND_RET_SUCCESS] => Ok(()),
+            [status] => Err(Error::Status(*status)),
+            _ => Err(Error::Garbled),
+        }
+    }
+    async fn read_byte(&mut self) -> Result<u8> {
+        match timeout(self.timeout, self.io.read_u8()).await {
+            Ok(byte) => Ok(byte?),
+            Err(_) => Err(Error::TimedOut),
+        }
+    }
+    /// Zeros may come before the ACK/NACK byte
+    async fn ack(&mut self) -> Result<()> {
+        loop {
+            match self.read_byte().await? {
+                0x00 => continue,
+                ACK => return Ok(()),
+                NACK => return Err(Error::Nack),
+                _ => return Err(Error::Garbled),
+            }
+        }
+    }
+    /// Packet sent by the bootloader, which wants an ACK back
+    async fn response(&mut self) -> Result<Vec<u8>> {
+        let size = loop {
+            match self.read_byte().await? {
+                0x00 => continue,
+                size if size < 2 => return Err(Error::Garbled),
+                size => break size,
+            }
+        };
+        let sum = self.read_byte().await?;
+        let mut data = vec![0; size as usize - 2];
+        match timeout(self.timeout, self.io.read_exact(&mut data)).await {
+            Ok(res) => res?,
+            Err(_) => return Err(Error::TimedOut),
+        };
+        if checksum(&data) != sum {
+            self.io.write_all(&[0x00, NACK]).await?;
+            return Err(Error::Garbled);
+        }
+        self.io.write_all(&[0x00, ACK]).await?;
+        Ok(data)
+    }
+}
+
+fn addr_size(address: u32, size: u32) -> [u8; 8] {
+    let mut args = [0; 8];
+    BigEndian::write_u32(&mut args[..4], address);
+    BigEndian::write_u32(&mut args[4..], size);
+    args
+}
+
+/// Flash contents of a simulated chip, starting at `Chip::flash_base`
+#[cfg(unix)]
+pub type SimFlash = Arc<Mutex<Vec<u8>>>;
+
+/// Fake ROM bootloader with erased flash. Returns the host end of the pipe,
+/// for `Bootloader::new`, and the flash to inspect afterwards.
+#[cfg(unix)]
+pub fn simulate(chip: Chip) -> io::Result<(UnixStream, SimFlash)> {
+    let (host, io) = UnixStream::pair()?;
+    let flash = Arc::new(Mutex::new(vec![0xFF; chip.flash_size() as usize]));
+    tokio::spawn(run_simulated(chip, io, flash.clone()));
+    Ok((host, flash))
+}
+
+#[cfg(unix)]
+async fn run_simulated(chip: Chip, mut io: UnixStream, flash: SimFlash) -> io::Result<()> {
+    let mut sync = [0; 2];
+    io.read_exact(&mut sync).await?;
+    let ack = if sync == SYNC { ACK } else { NACK };
+    io.write_all(&[0x00, ack]).await?;
+    let mut sim = SimChip {
+        chip,
+        flash,
+        download: (0, 0),
+        status: COMMAND_RET_SUCCESS,
+    };
+    loop {
+        let size = io.read_u8().await?;
+        let sum = io.read_u8().await?;
+        let mut packet = vec![0; (size as usize).saturating_sub(2)];
+        io.read_exact(&mut packet).await?;
+        if packet.is_empty() || checksum(&packet) != sum {
+            io.write_all(&[0x00, NACK]).await?;
+            continue;
+        }
+        io.write_all(&[0x00, ACK]).await?;
+        if packet == [COMMAND_RESET] {
+            return Ok(());
+        }
+        if let Some(rsp) = sim.handle(packet[0], &packet[1..]) {
+            let mut packet = vec![rsp.len() as u8 + 2, checksum(&rsp)];
+            packet.extend_from_slice(&rsp);
+            io.write_all(&packet).await?;
+            let mut ack = [0; 2];
+            io.read_exact(&mut ack).await?;
+        }
+    }
+}
+
+#[cfg(unix)]
+struct SimChip {
+    chip: Chip,
+    flash: SimFlash,
+    /// Where the next COMMAND_SEND_DATA goes, and how much is left of the download
+    download: (u32, u32),
+    /// For the next COMMAND_GET_STATUS
+    status: u8,
+}
+#[cfg(unix)]
+impl SimChip {
+    /// Offset into `flash`, if the range is inside it
+    fn offset(&self, address: u32, len: u32) -> Option<usize> {
+        if self.chip.contains(address, len) {
+            Some((address - self.chip.flash_base()) as usize)
+        } else {
+            None
+        }
+    }
+    fn erase(&mut self, address: u32, len: u32) -> u8 {
+        match self.offset(address, len) {
+            Some(at) => {
+                let mut flash = self.flash.lock().expect("simulated flash poisoned");
+                for cell in &mut flash[at..at + len as usize] {
+                    *cell = 0xFF;
+                }
+                COMMAND_RET_SUCCESS
+            }
+            None => COMMAND_RET_INVALID_ADR,
+        }
+    }
+    /// Returns the response packet, for commands that have one
+    fn handle(&mut self, cmd: u8, args: &[u8]) -> Option<Vec<u8>> {
+        let addr_size = || (BigEndian::read_u32(args), BigEndian::read_u32(&args[4..]));
+        match (cmd, args.len()) {
+            (COMMAND_PING, 0) => {}
+            (COMMAND_GET_STATUS, 0) => return Some(vec![self.status]),
+            (COMMAND_GET_CHIP_ID, 0) => return Some(vec![0x00, 0x00, 0xB9, 0x64]),
+            (COMMAND_DOWNLOAD, 8) => {
+                let (address, len) = addr_size();
+                self.status = match self.offset(address, len) {
+                    Some(_) if address % 4 == 0 && len % 4 == 0 => {
+                        self.download = (address, len);
+                        COMMAND_RET_SUCCESS
+                    }
+                    _ => COMMAND_RET_INVALID_ADR,
+                };
+            }
+            (COMMAND_SEND_DATA, len) => {
+                let (address, left) = self.download;
+                self.status = match self.offset(address, len as u32) {
+                    Some(at) if len as u32 <= left => {
+                        let mut flash = self.flash.lock().expect("simulated flash poisoned");
+                        // Flash bits only go from 1 to 0
+                        for (cell, byte) in flash[at..at + len].iter_mut().zip(args) {
+                            *cell &= byte;
+                        }
+                        self.download = (address + len as u32, left - len as u32);
+                        COMMAND_RET_SUCCESS
+                    }
+                    _ => COMMAND_RET_INVALID_ADR,
+                };
+            }
+            (COMMAND_ERASE, 8) if self.chip == Chip::Cc2538 => {
+                let (address, len) = addr_size();
+                self.status = self.erase(address, len);
+            }
+            (COMMAND_ERASE, 4) if self.chip == Chip::Cc26xx => {
+                let address = BigEndian::read_u32(args);
+                let page = self.chip.page_size();
+                self.status = self.erase(address - address % page, page);
+            }
+            (COMMAND_CRC32, 8) | (COMMAND_CRC32, 12) => {
+                let (address, len) = addr_size();
+                let crc = match self.offset(address, len) {
+                    Some(at) => {
+                        let flash = self.flash.lock().expect("simulated flash poisoned");
+                        crc32(&flash[at..at + len as usize])
+                    }
+                    None => 0,
+                };
+                let mut rsp = vec![0; 4];
+                BigEndian::write_u32(&mut rsp, crc);
+                return Some(rsp);
+            }
+            _ => self.status = COMMAND_RET_UNKNOWN_CMD,
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_aligned_keeps_neighbours_sharing_a_word() {
+        let mut image = Image::default();
+        image.insert(0, &[0, 1, 2, 3, 4]);
+        image.insert(6, &[6, 7, 8, 9]);
+        image.insert(17, &[17]);
+        let aligned = image.word_aligned();
+        assert_eq!(
+            aligned.segments,
+            vec![
+                Segment {
+                    address: 0,
+                    data: vec![0, 1, 2, 3, 4, 0xFF, 6, 7, 8, 9, 0xFF, 0xFF],
+                },
+                Segment {
+                    address: 16,
+                    data: vec![0xFF, 17, 0xFF, 0xFF],
+                },
+            ]
+        );
+    }
+
+    /// Flashes `image` over whatever `dirty` left in flash, returns the flash afterwards
+    #[cfg(unix)]
+    async fn flash_simulated(chip: Chip, image: &Image, dirty: &[u32]) -> Vec<u8> {
+        let (io, flash) = simulate(chip).unwrap();
+        for &address in dirty {
+            flash.lock().unwrap()[(address - chip.flash_base()) as usize] = 0x00;
+        }
+        Bootloader::new(io, chip).flash(image).await.unwrap();
+        let flash = flash.lock().unwrap();
+        flash.clone()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn flashes_cc2538_from_ihex() {
+        let chip = Chip::Cc2538;
+        let image = Image::from_ihex(
+            ":020000040020DA\n:04001000DEADBEEFB4\n:03001500010203E2\n:00000001FF\n",
+        )
+        .unwrap();
+        let base = chip.flash_base() as usize;
+        // Erased with its page, and another page left alone
+        let dirty = [base as u32 + 0x08, base as u32 + 0x1_0000];
+        let flash = flash_simulated(chip, &image, &dirty).await;
+        let mut expected = [0xFF; 0x20];
+        expected[0x10..0x14].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        expected[0x15..0x18].copy_from_slice(&[0x01, 0x02, 0x03]);
+        assert_eq!(flash[..0x20], expected[..]);
+        assert!(flash[0x20..0x1_0000].iter().all(|&byte| byte == 0xFF));
+        assert_eq!(flash[0x1_0000], 0x00);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn flashes_cc26xx_across_pages() {
+        let chip = Chip::Cc26xx;
+        let page = chip.page_size();
+        let long: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let mut image = Image::default();
+        image.insert(0x10, &long);
+        // Unaligned, and spilling into the second page
+        image.insert(page - 3, &[1, 2, 3, 4, 5, 6, 7]);
+        let dirty = [0x08, page + 0x100, 4 * page];
+        let flash = flash_simulated(chip, &image, &dirty).await;
+        assert_eq!(flash[0x08], 0xFF);
+        assert_eq!(flash[0x10..0x10 + 600], long[..]);
+        let page = page as usize;
+        assert_eq!(flash[page - 3..page + 4], [1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(flash[page + 0x100], 0xFF);
+        assert_eq!(flash[4 * page], 0x00);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reads_chip_id() {
+        let (io, _flash) = simulate(Chip::Cc26xx).unwrap();
+        let mut bootloader = Bootloader::new(io, Chip::Cc26xx);
+        bootloader.sync().await.unwrap();
+        assert_eq!(bootloader.chip_id().await.unwrap(), 0xB964);
+    }
+}
@@ -0,0 +1,27 @@
+//! Lower case hex strings for bytes in JSON files, usable as `#[serde(with = "crate::hex")]`
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `None` unless it is all hex digit pairs
+pub fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub fn serialize<S: Serializer>(bytes: &[u8], ser: S) -> Result<S::Ok, S::Error> {
+    ser.serialize_str(&encode(bytes))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(de)?;
+    decode(&hex).ok_or_else(|| D::Error::custom("not a hex string"))
+}
@@ -1,103 +1,118 @@
+use super::bus::{AreqFilter, Event};
 use super::cmd;
-use super::znp::Sender;
+use super::network::{self, ConfigError, NetworkConfig};
+use super::nv::{self, NvError};
+use super::znp::{AreqError, Sender, SreqError};
+use super::znp_codec::Subsys;
+use crate::areq::AreqIn;
 use cmd::sys::{ResetReq, ResetType};
-use cmd::types::ShortAddr;
-use cmd::zb::{ConfigId, ReadConfig};
-pub async fn init(znp: &mut Sender) {
-    use cmd::zb::{ZbDeviceInfoProp, ZbGetDeviceInfoReq};
-    for param in vec![
-        ZbDeviceInfoProp::DevState,
-        ZbDeviceInfoProp::IeeeAddr,
-        ZbDeviceInfoProp::ShortAddr,
-    ] {
-        let cmd = ZbGetDeviceInfoReq { param };
-        let res = znp.sreq(cmd).await;
-        println!("{:x?}", res);
+use cmd::types::{IEEEAddr, ShortAddr};
+use cmd::zb::{ZbDeviceInfoProp, ZbGetDeviceInfoReq};
+use cmd::zdo::{self, DeviceState, StartupFromApp, StartupFromAppStatus, StateChange};
+use cmd::Areq;
+use num_traits::FromPrimitive;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+use tracing::{debug, error, info};
+
+/// How long the reset after writing the network config may take
+const RESET_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long forming or restoring the network may take
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// NV item the stack leaves alone, set once a network was formed
+pub const ZNP_HAS_CONFIGURED: u16 = 0x0F00;
+pub const ZNP_CONFIGURED: u8 = 0x55;
+
+/// The coordinator is up, as ZB_GET_DEVICE_INFO reports it
+#[derive(Debug)]
+pub struct Started {
+    pub state: DeviceState,
+    /// A new network rather than the one restored from NV
+    pub formed: bool,
+    pub channel: u8,
+    pub pan_id: u16,
+    pub ext_pan_id: u64,
+    pub ieee_addr: IEEEAddr,
+    pub short_addr: ShortAddr,
+}
+
+#[derive(Debug)]
+pub enum StartError {
+    Sreq(SreqError),
+    Nv(NvError),
+    Config(ConfigError),
+    /// ZDO_STARTUP_FROM_APP answered Leave, the device won't start
+    Left,
+    /// Never reached `DeviceState::ZbCoord`, stuck in this one
+    TimedOut(DeviceState),
+    /// ZB_GET_DEVICE_INFO reported a state we don't know
+    UnknownState(u8),
+}
+impl From<SreqError> for StartError {
+    fn from(err: SreqError) -> Self {
+        StartError::Sreq(err)
     }
+}
+impl From<NvError> for StartError {
+    fn from(err: NvError) -> Self {
+        StartError::Nv(err)
+    }
+}
+impl From<ConfigError> for StartError {
+    fn from(err: ConfigError) -> Self {
+        StartError::Config(err)
+    }
+}
 
-    use cmd::sys::NvRead;
-    let cmd = NvRead {
-        /// ZNP_HAS_CONFIGURED
-        id: 0x0F00,
-        offset: 0x00,
-    };
-    let res = znp.sreq(cmd).await;
-    // Expecting [0x55]
-    println!("{:x?}", res);
-    struct NvParam {
-        configid: ConfigId,
-        len: u8,
-        value: Vec<u8>,
+#[derive(Debug)]
+pub enum InitError {
+    Config(ConfigError),
+    Start(StartError),
+    Sreq(SreqError),
+}
+impl From<ConfigError> for InitError {
+    fn from(err: ConfigError) -> Self {
+        InitError::Config(err)
     }
-    let startup_option = NvParam {
-        configid: ConfigId::StartupOption,
-        len: 0x01,
-        value: vec![0x00],
-    };
-    let pan_id = NvParam {
-        configid: ConfigId::Panid,
-        len: 0x02,
-        // Koenk default is 0x1a62, shepherd value is 0xffff
-        value: vec![0x62, 0x1A],
-    };
-    let ext_pan_id = NvParam {
-        configid: ConfigId::ExtendedPanId,
-        len: 0x08,
-        value: vec![0xDD, 0xDD, 0xDD, 0xDD, 0xDD, 0xDD, 0xDD, 0xDD],
-    };
-    let channel_list = NvParam {
-        configid: ConfigId::Chanlist,
-        len: 0x04,
-        // Little endian. Default is 0x00000800 for CH11;  Ex: value: [ 0x00, 0x00, 0x00, 0x04 ] for CH26, [ 0x00, 0x00, 0x20, 0x00 ] for CH15.
-        value: vec![0x00, 0x08, 0x00, 0x00],
-    };
-    let logical_type = NvParam {
-        configid: ConfigId::LogicalType,
-        len: 0x01,
-        value: vec![0x00],
-    };
-    let precfgkey = NvParam {
-        configid: ConfigId::Precfgkey,
-        len: 0x10,
-        value: vec![
-            0x01, 0x03, 0x05, 0x07, 0x09, 0x0B, 0x0D, 0x0F, 0x00, 0x02, 0x04, 0x06, 0x08, 0x0A,
-            0x0C, 0x0D,
-        ],
-    };
-    let precfgkeys_enable = NvParam {
-        configid: ConfigId::PrecfgkeysEnable,
-        len: 0x01,
-        value: vec![0x00], // value: 0 (FALSE) only coord defualtKey need to be set, and OTA to set other devices in the network.
-                           // value: 1 (TRUE) Not only coord, but also all devices need to set their defualtKey (the same key). Or they can't not join the network.
-    };
-    let zdo_direct_cb = NvParam {
-        configid: ConfigId::ZdoDirectCb,
-        len: 0x01,
-        value: vec![0x01],
-    };
-    let all_params = [
-        startup_option,
-        pan_id,
-        ext_pan_id,
-        channel_list,
-        logical_type,
-        precfgkey,
-        precfgkeys_enable,
-        zdo_direct_cb,
-    ];
-    for param in &all_params {
-        let cmd = ReadConfig { id: param.configid };
-        let res = znp.sreq(cmd).await;
-        println!("expected {:x?}", &param.value);
-        println!("got      {:x?}", res.unwrap().value);
+}
+impl From<StartError> for InitError {
+    fn from(err: StartError) -> Self {
+        InitError::Start(err)
     }
+}
+impl From<SreqError> for InitError {
+    fn from(err: SreqError) -> Self {
+        InitError::Sreq(err)
+    }
+}
 
-    use cmd::zdo::StartupFromApp;
-    let cmd = StartupFromApp {
-        delay: 100, /* this was 100, why? When would you want this? */
+pub async fn init(znp: &Sender, config: &NetworkConfig) -> Result<(), InitError> {
+    let reconciled = match network::reconcile(znp, config, RESET_TIMEOUT).await {
+        Err(ConfigError::DefaultKey) => {
+            error!("refusing to form a network with the default key, set allow_default_key");
+            return Err(ConfigError::DefaultKey.into());
+        }
+        res => res?,
     };
-    let res = znp.sreq(cmd).await;
-    println!("StartupFromApp {:x?}", res);
+    if reconciled.changes.is_empty() {
+        debug!("network config up to date");
+    } else {
+        info!(
+            changed = reconciled.changes.len(),
+            reformed = reconciled.reformed,
+            "network config written"
+        );
+    }
+
+    let started = start(znp, START_TIMEOUT).await?;
+    info!(
+        channel = started.channel,
+        pan_id = started.pan_id,
+        formed = started.formed,
+        "coordinator started {:x?}",
+        started
+    );
 
     use cmd::zdo::NodeDescReq;
     let cmd = NodeDescReq {
@@ -105,7 +120,7 @@ pub async fn init(znp: &mut Sender) {
         query_addr: ShortAddr(0),
     };
     let res = znp.sreq(cmd).await;
-    println!("NodeDescReq {:x?}", res.unwrap().status);
+    debug!(status = res?.status, "NodeDescReq");
 
     use cmd::zdo::ActiveEpReq;
     let cmd = ActiveEpReq {
@@ -113,7 +128,7 @@ pub async fn init(znp: &mut Sender) {
         query_addr: ShortAddr(0),
     };
     let res = znp.sreq(cmd).await;
-    println!("Active EPs {:x?}", res.unwrap());
+    debug!("Active EPs {:x?}", res?);
 
     use cmd::af::Register;
     let endpoint_profile_ids = [0x0104, 0x0101, 0x0105, 0x0107, 0x0108, 0x0109];
@@ -126,7 +141,7 @@ pub async fn init(znp: &mut Sender) {
             ..Default::default()
         };
         let res = znp.sreq(cmd).await;
-        println!("Register ep {:x} {:x?}", ep, res.unwrap());
+        debug!(ep, app_prof, "Register {:x?}", res?);
     }
     let cmd = Register {
         ep: 11,
@@ -137,14 +152,14 @@ pub async fn init(znp: &mut Sender) {
         ..Default::default()
     };
     let res = znp.sreq(cmd).await;
-    println!("Register ep 11 {:x?}", res.unwrap());
+    debug!(ep = 11, "Register {:x?}", res?);
 
     let cmd = ActiveEpReq {
         dest_addr: ShortAddr(0),
         query_addr: ShortAddr(0),
     };
     let res = znp.sreq(cmd).await;
-    println!("Active EPs {:x?}", res.unwrap());
+    debug!("Active EPs {:x?}", res?);
 
     use cmd::zdo::MgmtPermitJoinReq;
     let cmd = MgmtPermitJoinReq {
@@ -154,7 +169,7 @@ pub async fn init(znp: &mut Sender) {
         tc_significance: 0,
     };
     let res = znp.sreq(cmd).await;
-    println!("MgmtPermitJoinReq {:x?}", res.unwrap().status);
+    debug!(status = res?.status, "MgmtPermitJoinReq");
     let cmd = MgmtPermitJoinReq {
         addr_mode: 0x0f,
         dest_addr: ShortAddr(0xfffc),
@@ -162,7 +177,7 @@ pub async fn init(znp: &mut Sender) {
         tc_significance: 0,
     };
     let res = znp.sreq(cmd).await;
-    println!("MgmtPermitJoinReq {:x?}", res.unwrap().status);
+    debug!(status = res?.status, "MgmtPermitJoinReq");
     // let cmd = MgmtPermitJoinReq {
     //     addr_mode: 0x02,
     //     dest_addr: ShortAddr(0x0000),
@@ -171,9 +186,74 @@ pub async fn init(znp: &mut Sender) {
     // };
     // let res = znp.sreq(cmd).await;
     // println!("MgmtPermitJoinReq {:x?}", res.unwrap().status);
+    Ok(())
+}
+
+/// Starts the coordinator and waits for DEV_ZB_COORD. A stick that never formed a
+/// network is cleared first, so it forms a new one from its configuration.
+pub async fn start(znp: &Sender, timeout: Duration) -> Result<Started, StartError> {
+    let configured = nv::read(znp, ZNP_HAS_CONFIGURED).await? == Some(vec![ZNP_CONFIGURED]);
+    if !configured {
+        info!("not configured yet, forming a new network");
+        network::clear_state(znp, RESET_TIMEOUT).await?;
+    }
+
+    let mut states = znp.subscribe(AreqFilter {
+        subsys: Some(Subsys::ZDO),
+        cmd_id: Some(StateChange::CMD_ID),
+        ..Default::default()
+    });
+    let cmd = StartupFromApp {
+        delay: 100, /* this was 100, why? When would you want this? */
+    };
+    let formed = match znp.sreq(cmd).await?.status {
+        StartupFromAppStatus::Restored => false,
+        StartupFromAppStatus::New => true,
+        StartupFromAppStatus::Leave => return Err(StartError::Left),
+    };
+    // Already up if startup is rerun without a reset in between
+    let mut state = device_state(znp).await?;
+    let deadline = Instant::now() + timeout;
+    while state != DeviceState::ZbCoord {
+        match time::timeout_at(deadline, states.recv()).await {
+            Err(_) => return Err(StartError::TimedOut(state)),
+            Ok(Some(Event::Areq(areq))) => {
+                if let Areq::Zdo(zdo::In::StateChange(change)) = &*areq {
+                    debug!(state = ?change.state, "state change");
+                    state = change.state;
+                }
+            }
+            Ok(Some(Event::Lagged(_))) => state = device_state(znp).await?,
+            Ok(None) => return Err(SreqError::SerialPortGone.into()),
+        }
+    }
+    if !configured {
+        nv::write(znp, ZNP_HAS_CONFIGURED, &[ZNP_CONFIGURED]).await?;
+    }
+
+    Ok(Started {
+        state,
+        formed,
+        channel: device_info(znp, ZbDeviceInfoProp::Channel).await? as u8,
+        pan_id: device_info(znp, ZbDeviceInfoProp::PanId).await? as u16,
+        ext_pan_id: device_info(znp, ZbDeviceInfoProp::ExtPanId).await?,
+        ieee_addr: IEEEAddr(device_info(znp, ZbDeviceInfoProp::IeeeAddr).await?),
+        short_addr: ShortAddr(device_info(znp, ZbDeviceInfoProp::ShortAddr).await? as u16),
+    })
+}
+
+/// ZB_GET_DEVICE_INFO, every property comes back in 8 little endian bytes
+pub async fn device_info(znp: &Sender, param: ZbDeviceInfoProp) -> Result<u64, SreqError> {
+    let rsp = znp.sreq(ZbGetDeviceInfoReq { param }).await?;
+    Ok(u64::from_le_bytes(rsp.value))
+}
+
+async fn device_state(znp: &Sender) -> Result<DeviceState, StartError> {
+    let state = device_info(znp, ZbDeviceInfoProp::DevState).await? as u8;
+    DeviceState::from_u8(state).ok_or(StartError::UnknownState(state))
 }
 
-pub async fn soft_reset(znp: &mut Sender) {
+pub async fn soft_reset(znp: &Sender) -> Result<(), AreqError> {
     znp.areq(ResetReq {
         typ: ResetType::Soft,
     })
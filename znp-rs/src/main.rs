@@ -1,11 +1,22 @@
 #![warn(clippy::all)]
 
 mod areq;
+mod backup;
+mod bus;
+mod capture;
+mod flasher;
+mod hex;
 mod serde_znp;
+#[cfg(unix)]
+mod sim;
 mod sreq;
+mod transport;
 mod znp_codec;
 
 mod init_coord;
+mod network;
+mod nv;
+mod pcap;
 
 mod cmd;
 mod zcl;
@@ -13,49 +24,116 @@ mod zcl;
 mod znp;
 
 use futures_util::StreamExt;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 
 use cmd::types::ShortAddr;
 
 #[tokio::main]
 async fn main() {
-    let (znp, rec) = znp::Sender::from_path("/dev/ttyACM0");
-    let znp = std::sync::Arc::new(futures_util::lock::Mutex::new(znp));
+    // RUST_LOG=znp_rs=trace shows every frame
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+    match std::env::args().nth(1).as_deref() {
+        Some("flash") => return flash().await,
+        Some("backup") => return backup(false).await,
+        Some("restore") => return backup(true).await,
+        Some("pcap") => return export_pcap(),
+        _ => {}
+    }
+    let port = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "/dev/ttyACM0".to_owned());
+    let port: transport::Port = port.parse().expect("Invalid port");
+    let capture = std::env::args().nth(2).map(|path| {
+        if path.ends_with(".pcap") {
+            let file = std::fs::File::create(path).expect("Couldn't create pcap file");
+            capture::Capture::new(pcap::PcapWriter::new(file).expect("Couldn't write pcap"))
+        } else {
+            capture::Capture::create(path).expect("Couldn't create capture file")
+        }
+    });
+    // ZNP_NETWORK_CONFIG=network.json
+    let network_path =
+        std::env::var("ZNP_NETWORK_CONFIG").unwrap_or_else(|_| "network.json".to_owned());
+    let opts = znp::Options {
+        connect: transport::ConnectOptions {
+            // Sticks differ, probe rather than guess
+            serial: transport::SerialConfig::auto(),
+            ..Default::default()
+        },
+        capture,
+        reconnect: true,
+        skip_bootloader: Some(Default::default()),
+        watchdog: Some(Default::default()),
+        startup: Some(znp::startup(move |znp| {
+            let network_path = network_path.clone();
+            async move {
+                let network = network::NetworkConfig::load_or_generate(&znp, &network_path)
+                    .await
+                    .map_err(init_coord::InitError::Config)?;
+                init_coord::init(&znp, &network).await
+            }
+        })),
+        ..Default::default()
+    };
+    let znp = znp::Sender::connect(&port, &opts)
+        .await
+        .expect("Couldn't open port");
+    let rec = znp.subscribe(Default::default());
+    let mut status = znp.status();
+    tokio::spawn(async move {
+        while let Ok(status) = status.recv().await {
+            info!("connection {:?}", status);
+        }
+    });
     let znp2 = znp.clone();
     let (close_tx, mut close_rx) = tokio::sync::mpsc::channel::<()>(1);
     tokio::spawn(async {
         let mut rec = rec;
         let znp = znp2;
         let _close_tx = close_tx;
-        while let Some(areq) = rec.next().await {
-            println!("AREQ: {:x?}", &areq);
-            if let Some(sender) = areq.sender() {
-                println!("Sender: {:x?}", sender);
-            }
-            match areq {
+        while let Some(event) = rec.recv().await {
+            let areq = match event {
+                bus::Event::Areq(areq) => areq,
+                bus::Event::Lagged(count) => {
+                    warn!(count, "missed AREQs");
+                    continue;
+                }
+            };
+            info!(
+                subsys = ?areq.subsys(),
+                cmd_id = areq.cmd_id(),
+                sender = ?areq.sender(),
+                cluster = ?areq.cluster(),
+                "AREQ {:x?}",
+                areq
+            );
+            match &*areq {
                 cmd::Areq::Af(cmd::af::In::IncomingMsg(incoming)) => {
-                    let frame = zcl::frame::ZclFrame::parse(bytes::Bytes::from(incoming.data));
-                    println!("ZclFrame: {:x?}", frame);
+                    let frame =
+                        zcl::frame::ZclFrame::parse(bytes::Bytes::from(incoming.data.clone()));
+                    debug!("ZclFrame {:x?}", frame);
                     let cluster = zcl::clusters::ClusterId::from(incoming.cluster);
-                    println!("Cluster: {:x?}", cluster);
                     if let Ok(cluster) = cluster {
                         let msg = zcl::clusters::In::parse(cluster, frame);
-                        println!("{:x?}", msg);
+                        info!(?cluster, "ZCL {:x?}", msg);
+                    } else {
+                        debug!(cluster = incoming.cluster, "unknown cluster");
                     }
                 }
                 cmd::Areq::Zdo(cmd::zdo::In::EndDevAnnce(announcement)) => {
                     // tokio::timer::delay_for(std::time::Duration::from_millis(100)).await;
-                    let mut znp = znp.lock().await;
-                    interrogate(&mut znp, announcement.nwk_addr).await;
+                    let znp = znp.clone();
+                    let device = announcement.nwk_addr;
+                    let span = info_span!("interrogate", nwk_addr = device.0);
+                    tokio::spawn(async move { interrogate(&znp, device).await }.instrument(span));
                 }
                 _ => {}
             };
         }
     });
 
-    {
-        let mut znp = znp.lock().await;
-        init_coord::init(&mut znp).await;
-    }
     close_rx.next().await;
 
     // use cmd::sys::StartTimer;
@@ -68,12 +146,85 @@ async fn main() {
     //     println!("StartTimer {:x?}", res);
     // }
 
-    // init_coord::soft_reset(&mut znp).await;
+    // init_coord::soft_reset(&znp).await;
 
-    // blink_forever(&mut znp).await;
+    // blink_forever(&znp).await;
 }
 
-async fn interrogate(znp: &mut znp::Sender, device: ShortAddr) {
+/// `znp-rs flash <port> <image.hex|image.bin> [cc2538|cc26xx]`, chip in bootloader mode
+async fn flash() {
+    let mut args = std::env::args().skip(2);
+    let port: transport::Port = args
+        .next()
+        .expect("Missing port")
+        .parse()
+        .expect("Invalid port");
+    let path = args.next().expect("Missing image");
+    let chip = match args.next().as_deref() {
+        Some("cc2538") => flasher::Chip::Cc2538,
+        Some("cc26xx") | None => flasher::Chip::Cc26xx,
+        Some(chip) => panic!("Unknown chip {}", chip),
+    };
+    let image = flasher::Image::load(&path, chip.flash_base()).expect("Couldn't read image");
+    let io = port
+        .open(&Default::default())
+        .await
+        .expect("Couldn't open port");
+    match flasher::Bootloader::new(io, chip).flash(&image).await {
+        Ok(()) => info!("done"),
+        Err(err) => error!(error = ?err, "flashing failed"),
+    }
+}
+
+/// `znp-rs backup <port> <file.json>` or `znp-rs restore <port> <file.json>`
+async fn backup(restore: bool) {
+    let mut args = std::env::args().skip(2);
+    let port: transport::Port = args
+        .next()
+        .expect("Missing port")
+        .parse()
+        .expect("Invalid port");
+    let path = args.next().expect("Missing backup file");
+    let opts = znp::Options {
+        skip_bootloader: Some(Default::default()),
+        ..Default::default()
+    };
+    let znp = znp::Sender::connect(&port, &opts)
+        .await
+        .expect("Couldn't open port");
+    let res = if restore {
+        match backup::Backup::load(&path) {
+            Ok(backup) => backup::restore(&znp, &backup).await,
+            Err(err) => Err(err),
+        }
+    } else {
+        match backup::backup(&znp).await {
+            Ok(backup) => backup.save(&path),
+            Err(err) => Err(err),
+        }
+    };
+    match res {
+        Ok(()) if restore => match init_coord::soft_reset(&znp).await {
+            Ok(()) => info!(path = %path, "restored"),
+            Err(err) => error!(error = ?err, "restored, but couldn't reset"),
+        },
+        Ok(()) => info!(path = %path, "backed up"),
+        Err(err) => error!(error = ?err, "failed"),
+    }
+}
+
+/// `znp-rs pcap <capture.jsonl> <out.pcap>`, AF traffic of a stored capture for Wireshark
+fn export_pcap() {
+    let mut args = std::env::args().skip(2);
+    let capture = args.next().expect("Missing capture file");
+    let out = args.next().expect("Missing pcap file");
+    match pcap::export(&capture, &out) {
+        Ok(count) => info!(path = %out, count, "exported"),
+        Err(err) => error!(error = %err, "export failed"),
+    }
+}
+
+async fn interrogate(znp: &znp::Sender, device: ShortAddr) {
     use cmd::types::Endpoint;
 
     {
@@ -81,27 +232,28 @@ async fn interrogate(znp: &mut znp::Sender, device: ShortAddr) {
             dest_addr: device,
             query_addr: device,
         };
-        let res = znp.sreq(cmd).await;
-        println!("PowerDescReq {:x?}", res);
+        let res = znp.sreq_callback(cmd).await;
+        info!("PowerDescRsp {:x?}", res);
     }
 
-    {
+    let active_eps = {
         let cmd = cmd::zdo::ActiveEpReq {
             dest_addr: device,
             query_addr: device,
         };
-        let res = znp.sreq(cmd).await;
-        println!("ActiveEpReq {:x?}", res);
-    }
+        let res = znp.sreq_callback(cmd).await;
+        info!("ActiveEpRsp {:x?}", res);
+        res.map(|rsp| rsp.active_eps).unwrap_or_default()
+    };
 
-    {
+    for ep in active_eps {
         let cmd = cmd::zdo::SimpleDescReq {
             dest_addr: device,
             query_addr: device,
-            endpoint: Endpoint(1),
+            endpoint: Endpoint(ep),
         };
-        let res = znp.sreq(cmd).await;
-        println!("SimpleDescReq {:x?}", res);
+        let res = znp.sreq_callback(cmd).await;
+        info!(ep, "SimpleDescRsp {:x?}", res);
     }
 
     {
@@ -109,12 +261,12 @@ async fn interrogate(znp: &mut znp::Sender, device: ShortAddr) {
             dest_addr: device,
             query_addr: device,
         };
-        let res = znp.sreq(cmd).await;
-        println!("ComplexDescReq {:x?}", res);
+        let res = znp.sreq_callback(cmd).await;
+        info!("ComplexDescRsp {:x?}", res);
     }
 }
 
-async fn blink_forever(znp: &mut znp::Sender) {
+async fn blink_forever(znp: &znp::Sender) {
     use cmd::util::{UtilLedControl, UtilLedControlRsp};
     for id in 1..=2 {
         let cmd = UtilLedControl {
@@ -122,7 +274,7 @@ async fn blink_forever(znp: &mut znp::Sender) {
             mode: false,
         };
         let res = znp.sreq(cmd).await;
-        println!("Light Off {:x?}", res);
+        debug!(led_id = id, "Light Off {:x?}", res);
     }
 
     let led_id = 2;
@@ -133,7 +285,7 @@ async fn blink_forever(znp: &mut znp::Sender) {
         let res = znp.sreq(cmd).await;
         match res {
             Ok(UtilLedControlRsp { status: 0 }) => {}
-            _ => println!("Couldn't toggle light: {:?}", res),
+            _ => warn!(led_id, "couldn't toggle light: {:?}", res),
         }
         use std::time::Duration;
         tokio::time::delay_for(Duration::from_millis(if on { 1 } else { 4000 })).await;
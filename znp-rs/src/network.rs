@@ -0,0 +1,417 @@
+//! Desired network parameters, kept in a JSON file and reconciled against the stick's NV.
+use crate::cmd::sys::{ResetReq, ResetType};
+use crate::cmd::zb::{ConfigId, ReadConfig, WriteConfig};
+use crate::init_coord::{ZNP_CONFIGURED, ZNP_HAS_CONFIGURED};
+use crate::nv::{self, NvError};
+use crate::znp::{AreqError, Sender, SreqError, Status};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::time::Duration;
+use std::{fs, io, path::Path};
+use tokio::sync::broadcast::RecvError;
+use tracing::{info, warn};
+
+/// STARTUP_OPTION bit: forget the network and form anew from the configuration
+pub const STARTOPT_CLEAR_STATE: u8 = 0x02;
+/// LOGICAL_TYPE of a coordinator
+const LOGICAL_TYPE_COORDINATOR: u8 = 0x00;
+/// The Z-Stack sample key everyone ships, anyone can read such a network
+pub const DEFAULT_KEY: [u8; 16] = [
+    0x01, 0x03, 0x05, 0x07, 0x09, 0x0B, 0x0D, 0x0F, 0x00, 0x02, 0x04, 0x06, 0x08, 0x0A, 0x0C, 0x0D,
+];
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub pan_id: u16,
+    pub extended_pan_id: u64,
+    /// Channels 11 to 26 to pick from when forming
+    pub channels: Vec<u8>,
+    pub network_key: [u8; 16],
+    /// Every joining device must already have `network_key` rather than receive it
+    pub precfgkeys_enable: bool,
+    /// ZDO responses as AREQs rather than through ZDO_MSG_CB_REGISTER
+    pub zdo_direct_cb: bool,
+    /// Form a network with `DEFAULT_KEY` rather than refuse to
+    pub allow_default_key: bool,
+}
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            // Koenk default is 0x1a62, shepherd value is 0xffff
+            pan_id: 0x1A62,
+            extended_pan_id: 0xDDDD_DDDD_DDDD_DDDD,
+            channels: vec![11],
+            network_key: DEFAULT_KEY,
+            precfgkeys_enable: false,
+            zdo_direct_cb: true,
+            allow_default_key: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IO(io::Error),
+    Json(serde_json::Error),
+    Sreq(SreqError),
+    Areq(AreqError),
+    Nv(NvError),
+    /// Channels are 11 to 26
+    BadChannel(u8),
+    /// ZB_READ_CONFIGURATION or ZB_WRITE_CONFIGURATION failed
+    Status(ConfigId, u8),
+    /// ZB_READ_CONFIGURATION returned this many bytes, not what the item holds
+    Length(ConfigId, usize),
+    /// No SYS_RESET_IND after asking for the reset
+    NoReset,
+    /// `network_key` is `DEFAULT_KEY` without `allow_default_key`
+    DefaultKey,
+}
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        ConfigError::IO(err)
+    }
+}
+impl From<serde_json::Error> for ConfigError {
+    fn from(err: serde_json::Error) -> Self {
+        ConfigError::Json(err)
+    }
+}
+impl From<SreqError> for ConfigError {
+    fn from(err: SreqError) -> Self {
+        ConfigError::Sreq(err)
+    }
+}
+impl From<AreqError> for ConfigError {
+    fn from(err: AreqError) -> Self {
+        ConfigError::Areq(err)
+    }
+}
+impl From<NvError> for ConfigError {
+    fn from(err: NvError) -> Self {
+        ConfigError::Nv(err)
+    }
+}
+
+/// One configuration item that differed from NV
+pub struct Change {
+    pub id: ConfigId,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+/// Keeps the network key out of logs
+impl fmt::Debug for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut change = f.debug_struct("Change");
+        change.field("id", &self.id);
+        if is_secret(self.id) {
+            change.field("old", &Redacted).field("new", &Redacted);
+        } else {
+            change.field("old", &self.old).field("new", &self.new);
+        }
+        change.finish()
+    }
+}
+struct Redacted;
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+fn is_secret(id: ConfigId) -> bool {
+    id == ConfigId::Precfgkey
+}
+
+#[derive(Debug, Default)]
+pub struct Reconciled {
+    pub changes: Vec<Change>,
+    /// The network parameters changed, so the stick was told to form a new network
+    pub reformed: bool,
+}
+
+impl NetworkConfig {
+    /// Fresh key, PAN id and extended PAN id from the host's CSPRNG, for a first run.
+    /// Not SYS_RANDOM, on CC253x that is a 16-bit LFSR.
+    pub fn random() -> io::Result<Self> {
+        let mut entropy = [0; 26];
+        getrandom::getrandom(&mut entropy)?;
+        let mut network_key = [0; 16];
+        network_key.copy_from_slice(&entropy[..16]);
+        let mut ext = [0; 8];
+        ext.copy_from_slice(&entropy[16..24]);
+        Ok(NetworkConfig {
+            // 0x0000 is valid but looks unset, 0xFFF7 and up are reserved
+            pan_id: 0x0001 + u16::from_le_bytes([entropy[24], entropy[25]]) % 0xFFF6,
+            // All zeroes means pick any, all ones is reserved
+            extended_pan_id: match u64::from_le_bytes(ext) {
+                0 | 0xFFFF_FFFF_FFFF_FFFF => 0xDDDD_DDDD_DDDD_DDDD,
+                ext => ext,
+            },
+            network_key,
+            ..Default::default()
+        })
+    }
+    /// What the stick is configured with now, so `reconcile` finds nothing to change
+    pub async fn read(znp: &Sender) -> Result<Self, ConfigError> {
+        let pan_id = read(znp, ConfigId::Panid, 2).await?;
+        let ext_pan_id = read(znp, ConfigId::ExtendedPanId, 8).await?;
+        let chanlist = read(znp, ConfigId::Chanlist, 4).await?;
+        let key = read(znp, ConfigId::Precfgkey, 16).await?;
+        let precfgkeys_enable = read(znp, ConfigId::PrecfgkeysEnable, 1).await?;
+        let zdo_direct_cb = read(znp, ConfigId::ZdoDirectCb, 1).await?;
+        let mask = u32::from_le_bytes([chanlist[0], chanlist[1], chanlist[2], chanlist[3]]);
+        // Dropping a channel outside 11 to 26 would count as a change and re-form
+        if let Some(channel) = (0..32).find(|ch| mask & 1 << ch != 0 && !(11..=26).contains(ch)) {
+            return Err(ConfigError::BadChannel(channel));
+        }
+        let mut network_key = [0; 16];
+        network_key.copy_from_slice(&key);
+        let mut ext = [0; 8];
+        ext.copy_from_slice(&ext_pan_id);
+        Ok(NetworkConfig {
+            pan_id: u16::from_le_bytes([pan_id[0], pan_id[1]]),
+            extended_pan_id: u64::from_le_bytes(ext),
+            channels: (11..=26).filter(|ch| mask & 1 << ch != 0).collect(),
+            network_key,
+            precfgkeys_enable: precfgkeys_enable[0] != 0,
+            zdo_direct_cb: zdo_direct_cb[0] != 0,
+            allow_default_key: false,
+        })
+    }
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let config: NetworkConfig = serde_json::from_slice(&fs::read(path)?)?;
+        config.channel_mask()?;
+        Ok(config)
+    }
+    /// `load`, or on the first run `save` what the stick already uses. Only a stick that
+    /// never formed a network gets a `random` config, so paired devices are never dropped.
+    pub async fn load_or_generate(
+        znp: &Sender,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        match Self::load(path) {
+            Err(ConfigError::IO(err)) if err.kind() == io::ErrorKind::NotFound => {
+                let configured =
+                    nv::read(znp, ZNP_HAS_CONFIGURED).await? == Some(vec![ZNP_CONFIGURED]);
+                let config = if configured {
+                    Self::read(znp).await?
+                } else {
+                    Self::random()?
+                };
+                config.save(path)?;
+                if !configured {
+                    info!(path = %path.display(), "generated network config");
+                } else if config.network_key == DEFAULT_KEY {
+                    warn!(
+                        path = %path.display(),
+                        "adopted the stick's network, it uses the default key. Set \
+                         allow_default_key to keep it, or change network_key and re-pair \
+                         every device"
+                    );
+                } else {
+                    info!(path = %path.display(), "adopted the stick's network config");
+                }
+                Ok(config)
+            }
+            res => res,
+        }
+    }
+    /// Readable by the owner only, it holds the network key
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ConfigError> {
+        write_private(path, &serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+    pub fn channel_mask(&self) -> Result<u32, ConfigError> {
+        self.channels
+            .iter()
+            .try_fold(0, |mask, &channel| match channel {
+                11..=26 => Ok(mask | 1 << channel),
+                _ => Err(ConfigError::BadChannel(channel)),
+            })
+    }
+    /// Items as ZB_WRITE_CONFIGURATION takes them, and whether changing each means a new network
+    fn items(&self) -> Result<Vec<(ConfigId, Vec<u8>, bool)>, ConfigError> {
+        Ok(vec![
+            (ConfigId::Panid, self.pan_id.to_le_bytes().to_vec(), true),
+            (
+                ConfigId::ExtendedPanId,
+                self.extended_pan_id.to_le_bytes().to_vec(),
+                true,
+            ),
+            (
+                ConfigId::Chanlist,
+                self.channel_mask()?.to_le_bytes().to_vec(),
+                true,
+            ),
+            (ConfigId::LogicalType, vec![LOGICAL_TYPE_COORDINATOR], true),
+            (ConfigId::Precfgkey, self.network_key.to_vec(), true),
+            (
+                ConfigId::PrecfgkeysEnable,
+                vec![self.precfgkeys_enable.into()],
+                true,
+            ),
+            (
+                ConfigId::ZdoDirectCb,
+                vec![self.zdo_direct_cb.into()],
+                false,
+            ),
+        ])
+    }
+}
+
+/// Creates or replaces a file only its owner may read, for anything holding keys
+pub fn write_private(path: impl AsRef<Path>, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // `mode` only applies to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)
+}
+
+/// Writes whatever differs from `config`, then resets so it takes effect.
+/// Network parameter changes also set `STARTOPT_CLEAR_STATE` so the stick forms anew.
+pub async fn reconcile(
+    znp: &Sender,
+    config: &NetworkConfig,
+    reset_timeout: Duration,
+) -> Result<Reconciled, ConfigError> {
+    if config.network_key == DEFAULT_KEY && !config.allow_default_key {
+        return Err(ConfigError::DefaultKey);
+    }
+    let mut reconciled = Reconciled::default();
+    for (id, new, network) in config.items()? {
+        let rsp = znp.sreq(ReadConfig { id }).await?;
+        // A missing item reads as a failure, write it like any other difference
+        if rsp.status == 0 && rsp.value == new {
+            continue;
+        }
+        if is_secret(id) {
+            info!(config = ?id, "changing");
+        } else {
+            info!(config = ?id, "changing {:x?} to {:x?}", rsp.value, new);
+        }
+        write(znp, id, new.clone()).await?;
+        reconciled.reformed |= network;
+        reconciled.changes.push(Change {
+            id,
+            old: rsp.value,
+            new,
+        });
+    }
+    if reconciled.changes.is_empty() {
+        return Ok(reconciled);
+    }
+    if reconciled.reformed {
+        clear_state(znp, reset_timeout).await?;
+    } else {
+        reset(znp, reset_timeout).await?;
+    }
+    Ok(reconciled)
+}
+
+/// Forgets the network so the stick forms a new one from its configuration once started
+pub async fn clear_state(znp: &Sender, reset_timeout: Duration) -> Result<(), ConfigError> {
+    let id = ConfigId::StartupOption;
+    write(znp, id, vec![STARTOPT_CLEAR_STATE]).await?;
+    reset(znp, reset_timeout).await
+}
+
+/// Soft reset, waiting for SYS_RESET_IND
+pub async fn reset(znp: &Sender, reset_timeout: Duration) -> Result<(), ConfigError> {
+    let mut status = znp.status();
+    znp.areq(ResetReq {
+        typ: ResetType::Soft,
+    })
+    .await?;
+    let reset = async {
+        loop {
+            match status.recv().await {
+                Ok(Status::Reset(_)) => return true,
+                Err(RecvError::Closed) => return false,
+                _ => {}
+            }
+        }
+    };
+    match tokio::time::timeout(reset_timeout, reset).await {
+        Ok(true) => Ok(()),
+        _ => Err(ConfigError::NoReset),
+    }
+}
+
+async fn read(znp: &Sender, id: ConfigId, len: usize) -> Result<Vec<u8>, ConfigError> {
+    let rsp = znp.sreq(ReadConfig { id }).await?;
+    match (rsp.status, rsp.value.len()) {
+        (0, got) if got == len => Ok(rsp.value),
+        (0, got) => Err(ConfigError::Length(id, got)),
+        (status, _) => Err(ConfigError::Status(id, status)),
+    }
+}
+
+async fn write(znp: &Sender, id: ConfigId, value: Vec<u8>) -> Result<(), ConfigError> {
+    match znp.sreq(WriteConfig { id, value }).await?.status {
+        0 => Ok(()),
+        status => Err(ConfigError::Status(id, status)),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cmd::sys::NvId;
+    use crate::sim::Simulator;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("znp-rs-{}-{}.json", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn adopts_configured_stick() {
+        let key = [0x5A; 16];
+        let mut sim = Simulator::new();
+        let channel_15 = 1u32 << 15;
+        let dev = sim.device_mut();
+        dev.nv
+            .insert(NvId::legacy(ZNP_HAS_CONFIGURED), vec![ZNP_CONFIGURED]);
+        dev.config.insert(ConfigId::Precfgkey, key.to_vec());
+        dev.config
+            .insert(ConfigId::Chanlist, channel_15.to_le_bytes().to_vec());
+        let (io, _handle) = sim.spawn().unwrap();
+        let znp = Sender::new(io);
+        let path = temp_path("adopt");
+
+        let config = NetworkConfig::load_or_generate(&znp, &path).await.unwrap();
+        let saved = NetworkConfig::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.network_key, key);
+        assert_eq!(config.channels, vec![15]);
+        assert_eq!(saved.network_key, key);
+        let reconciled = reconcile(&znp, &config, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(reconciled.changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn generates_for_blank_stick() {
+        let (io, _handle) = Simulator::new().spawn().unwrap();
+        let znp = Sender::new(io);
+        let path = temp_path("generate");
+
+        let config = NetworkConfig::load_or_generate(&znp, &path).await.unwrap();
+        let saved = NetworkConfig::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(config.network_key, DEFAULT_KEY);
+        assert_eq!(saved.network_key, config.network_key);
+        assert_eq!(saved.extended_pan_id, config.extended_pan_id);
+    }
+}
@@ -0,0 +1,166 @@
+//! Whole NV items, however many frames they take.
+//!
+//! Legacy OSAL items go through `read`/`write`, falling back to the `_EXT` commands
+//! past offset 255. Z-Stack 3.x items addressed by system, item and sub id go through
+//! `read_id`/`write_id`.
+use crate::cmd::sys::{self, NvId};
+use crate::znp::{Sender, SreqError};
+
+/// Bytes per frame, small enough for every read and write command
+const CHUNK: usize = 240;
+
+#[derive(Debug)]
+pub enum NvError {
+    Sreq(SreqError),
+    /// Non-zero status, see `sys::NV_OPER_FAILED` and friends
+    Status(u8),
+    /// Nothing came back for an offset inside the item
+    Short {
+        offset: usize,
+    },
+    /// Legacy items are at most 0xFFFF bytes
+    TooLong(usize),
+}
+impl From<SreqError> for NvError {
+    fn from(err: SreqError) -> Self {
+        NvError::Sreq(err)
+    }
+}
+
+fn check(status: u8) -> Result<(), NvError> {
+    match status {
+        0 => Ok(()),
+        status => Err(NvError::Status(status)),
+    }
+}
+
+/// `None` if the item doesn't exist
+pub async fn read(znp: &Sender, id: u16) -> Result<Option<Vec<u8>>, NvError> {
+    let len = znp.sreq(sys::NvLength { id }).await?.length as usize;
+    if len == 0 {
+        return Ok(None);
+    }
+    let mut item = Vec::with_capacity(len);
+    while item.len() < len {
+        let offset = item.len();
+        let rsp = if offset <= 0xFF {
+            let offset = offset as u8;
+            znp.sreq(sys::NvRead { id, offset }).await?
+        } else {
+            let offset = offset as u16;
+            znp.sreq(sys::NvReadExt { id, offset }).await?
+        };
+        check(rsp.status)?;
+        if rsp.value.is_empty() {
+            return Err(NvError::Short { offset });
+        }
+        let take = rsp.value.len().min(len - offset);
+        item.extend_from_slice(&rsp.value[..take]);
+    }
+    Ok(Some(item))
+}
+
+/// Creates the item, or recreates it if its length differs
+pub async fn write(znp: &Sender, id: u16, value: &[u8]) -> Result<(), NvError> {
+    if value.len() > 0xFFFF {
+        return Err(NvError::TooLong(value.len()));
+    }
+    let len = znp.sreq(sys::NvLength { id }).await?.length;
+    if len != 0 && usize::from(len) != value.len() {
+        let item_len = len;
+        check(znp.sreq(sys::NvDelete { id, item_len }).await?.status)?;
+    }
+    if usize::from(len) != value.len() {
+        let init = sys::NvItemInit {
+            id,
+            item_len: value.len() as u16,
+            init_data: Vec::new(),
+        };
+        match znp.sreq(init).await?.status {
+            0 | sys::NV_ITEM_UNINIT => {}
+            status => return Err(NvError::Status(status)),
+        }
+    }
+    for (i, chunk) in value.chunks(CHUNK).enumerate() {
+        let offset = i * CHUNK;
+        let value = chunk.to_vec();
+        let rsp = if offset <= 0xFF {
+            let offset = offset as u8;
+            znp.sreq(sys::NvWrite { id, offset, value }).await?
+        } else {
+            let offset = offset as u16;
+            znp.sreq(sys::NvWriteExt { id, offset, value }).await?
+        };
+        check(rsp.status)?;
+    }
+    Ok(())
+}
+
+/// Nothing to do if the item doesn't exist
+pub async fn delete(znp: &Sender, id: u16) -> Result<(), NvError> {
+    let item_len = znp.sreq(sys::NvLength { id }).await?.length;
+    if item_len == 0 {
+        return Ok(());
+    }
+    check(znp.sreq(sys::NvDelete { id, item_len }).await?.status)
+}
+
+/// Z-Stack 3.x only, `None` if the item doesn't exist
+pub async fn read_id(znp: &Sender, id: NvId) -> Result<Option<Vec<u8>>, NvError> {
+    let len = znp.sreq(sys::SysNvLength { id }).await?.length as usize;
+    if len == 0 {
+        return Ok(None);
+    }
+    let mut item = Vec::with_capacity(len);
+    while item.len() < len {
+        let offset = item.len();
+        let read = sys::SysNvRead {
+            id,
+            offset: offset as u16,
+            len: (len - offset).min(CHUNK) as u8,
+        };
+        let rsp = znp.sreq(read).await?;
+        check(rsp.status)?;
+        if rsp.value.is_empty() {
+            return Err(NvError::Short { offset });
+        }
+        let take = rsp.value.len().min(len - offset);
+        item.extend_from_slice(&rsp.value[..take]);
+    }
+    Ok(Some(item))
+}
+
+/// Z-Stack 3.x only. Creates the item, or recreates it if its length differs.
+pub async fn write_id(znp: &Sender, id: NvId, value: &[u8]) -> Result<(), NvError> {
+    let len = znp.sreq(sys::SysNvLength { id }).await?.length as usize;
+    if len != value.len() {
+        if len != 0 {
+            check(znp.sreq(sys::SysNvDelete { id }).await?.status)?;
+        }
+        let create = sys::SysNvCreate {
+            id,
+            length: value.len() as u32,
+        };
+        match znp.sreq(create).await?.status {
+            0 | sys::NV_ITEM_UNINIT => {}
+            status => return Err(NvError::Status(status)),
+        }
+    }
+    for (i, chunk) in value.chunks(CHUNK).enumerate() {
+        let write = sys::SysNvWrite {
+            id,
+            offset: (i * CHUNK) as u16,
+            value: chunk.to_vec(),
+        };
+        check(znp.sreq(write).await?.status)?;
+    }
+    Ok(())
+}
+
+/// Z-Stack 3.x only, nothing to do if the item doesn't exist
+pub async fn delete_id(znp: &Sender, id: NvId) -> Result<(), NvError> {
+    match znp.sreq(sys::SysNvDelete { id }).await?.status {
+        0 | sys::NV_ITEM_UNINIT => Ok(()),
+        status => Err(NvError::Status(status)),
+    }
+}
@@ -0,0 +1,378 @@
+//! Wireshark export of AF traffic.
+//!
+//! Each `af::IncomingMsg` and `af::DataRequest` becomes a ZEP v2 packet over UDP/IPv4,
+//! wrapping an 802.15.4 data frame with synthesized NWK and APS headers.
+//! The APS payload is the ZCL frame as-is, see `zcl::frame::ZclFrame`.
+//! Security is not represented, everything is written as if unsecured.
+use crate::areq::AreqIn;
+use crate::capture::{self, Record, RecordSink};
+use crate::cmd::af::{DataRequest, IncomingMsg};
+use crate::sreq::Sreq;
+use crate::znp_codec::{Subsys, Type};
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::warn;
+
+const LINKTYPE_IPV4: u32 = 228;
+const ZEP_PORT: u16 = 17754;
+/// Seconds between the NTP epoch (1900) and the UNIX epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+/// Coordinator network address
+const COORD: u16 = 0x0000;
+/// aMaxPHYPacketSize, the longest 802.15.4 frame including the FCS
+const MAX_PSDU: usize = 127;
+/// AF_INCOMING_MSG has no profile, assume Home Automation
+const DEFAULT_PROFILE: u16 = 0x0104;
+
+/// Addressing of one APS data frame
+struct ApsFrame<'a> {
+    src_addr: u16,
+    dst_addr: u16,
+    /// Group address, replaces the destination endpoint
+    group: Option<u16>,
+    src_ep: u8,
+    dst_ep: u8,
+    cluster: u16,
+    counter: u8,
+    radius: u8,
+    lqi: u8,
+    payload: &'a [u8],
+}
+
+/// CRC-16/KERMIT, the 802.15.4 FCS
+fn fcs(buf: &[u8]) -> u16 {
+    buf.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ u16::from(byte), |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    let sum = (sum & 0xFFFF) + (sum >> 16);
+    !(((sum & 0xFFFF) + (sum >> 16)) as u16)
+}
+
+pub struct PcapWriter<W: Write> {
+    out: W,
+    /// Radio channel reported in the ZEP header
+    pub channel: u8,
+    seq: u32,
+}
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_u32::<LittleEndian>(0xA1B2_C3D4)?;
+        out.write_u16::<LittleEndian>(2)?;
+        out.write_u16::<LittleEndian>(4)?;
+        // thiszone, sigfigs
+        out.write_i32::<LittleEndian>(0)?;
+        out.write_u32::<LittleEndian>(0)?;
+        // snaplen
+        out.write_u32::<LittleEndian>(0xFFFF)?;
+        out.write_u32::<LittleEndian>(LINKTYPE_IPV4)?;
+        Ok(PcapWriter {
+            out,
+            channel: 11,
+            seq: 0,
+        })
+    }
+    /// `timestamp` in microseconds since the UNIX epoch, like `capture::Record`.
+    /// Returns `false` for payloads too long for one 802.15.4 frame, those are skipped
+    /// since the fragmentation the stack did on air isn't represented.
+    pub fn write_incoming(&mut self, timestamp: u64, msg: &IncomingMsg) -> io::Result<bool> {
+        let group = if msg.group != 0 {
+            Some(msg.group)
+        } else {
+            None
+        };
+        self.write_aps(
+            timestamp,
+            &ApsFrame {
+                src_addr: msg.addr.0,
+                dst_addr: if msg.was_broadcast { 0xFFFF } else { COORD },
+                group,
+                src_ep: msg.src_ep,
+                dst_ep: msg.dest_ep,
+                cluster: msg.cluster,
+                counter: msg.tr_seq,
+                radius: 0,
+                lqi: msg.link_quality,
+                payload: &msg.data,
+            },
+        )
+    }
+    pub fn write_outgoing(&mut self, timestamp: u64, req: &DataRequest) -> io::Result<bool> {
+        self.write_aps(
+            timestamp,
+            &ApsFrame {
+                src_addr: COORD,
+                dst_addr: req.dst_addr.0,
+                group: None,
+                src_ep: req.src_ep,
+                dst_ep: req.dst_ep,
+                cluster: req.cluster,
+                counter: req.trans_id,
+                radius: req.radius,
+                lqi: 0xFF,
+                payload: &req.data,
+            },
+        )
+    }
+    fn write_aps(&mut self, timestamp: u64, aps: &ApsFrame) -> io::Result<bool> {
+        self.seq = self.seq.wrapping_add(1);
+        let broadcast = aps.dst_addr >= 0xFFF8;
+        let mut mac = Vec::with_capacity(127);
+        // MAC: data, PAN ID compression, short destination and source
+        mac.write_u16::<LittleEndian>(0x8841)?;
+        mac.write_u8(self.seq as u8)?;
+        mac.write_u16::<LittleEndian>(0xFFFF)?;
+        mac.write_u16::<LittleEndian>(aps.dst_addr)?;
+        mac.write_u16::<LittleEndian>(aps.src_addr)?;
+        // NWK: data, protocol version 2
+        mac.write_u16::<LittleEndian>(0x0008)?;
+        mac.write_u16::<LittleEndian>(aps.dst_addr)?;
+        mac.write_u16::<LittleEndian>(aps.src_addr)?;
+        mac.write_u8(aps.radius)?;
+        mac.write_u8(self.seq as u8)?;
+        // APS: data, delivery mode unicast/broadcast/group
+        match aps.group {
+            Some(group) => {
+                mac.write_u8(0x0C)?;
+                mac.write_u16::<LittleEndian>(group)?;
+            }
+            None => {
+                mac.write_u8(if broadcast { 0x08 } else { 0x00 })?;
+                mac.write_u8(aps.dst_ep)?;
+            }
+        }
+        mac.write_u16::<LittleEndian>(aps.cluster)?;
+        mac.write_u16::<LittleEndian>(DEFAULT_PROFILE)?;
+        mac.write_u8(aps.src_ep)?;
+        mac.write_u8(aps.counter)?;
+        mac.extend_from_slice(aps.payload);
+        let crc = fcs(&mac);
+        mac.write_u16::<LittleEndian>(crc)?;
+        if mac.len() > MAX_PSDU {
+            warn!(
+                len = mac.len(),
+                cluster = aps.cluster,
+                "APS frame too long for 802.15.4, skipping"
+            );
+            return Ok(false);
+        }
+
+        let mut zep = Vec::with_capacity(32 + mac.len());
+        zep.extend_from_slice(b"EX");
+        // Version 2, data
+        zep.write_u8(2)?;
+        zep.write_u8(1)?;
+        zep.write_u8(self.channel)?;
+        zep.write_u16::<BigEndian>(0)?;
+        // CRC mode, the frame ends with a real FCS
+        zep.write_u8(1)?;
+        zep.write_u8(aps.lqi)?;
+        let secs = timestamp / 1_000_000;
+        let micros = timestamp % 1_000_000;
+        zep.write_u32::<BigEndian>((secs + NTP_UNIX_OFFSET) as u32)?;
+        zep.write_u32::<BigEndian>(((micros << 32) / 1_000_000) as u32)?;
+        zep.write_u32::<BigEndian>(self.seq)?;
+        zep.extend_from_slice(&[0; 10]);
+        zep.write_u8(mac.len() as u8)?;
+        zep.extend_from_slice(&mac);
+
+        let udp_len = 8 + zep.len();
+        let ip_len = 20 + udp_len;
+        let mut ip = Vec::with_capacity(ip_len);
+        ip.extend_from_slice(&[0x45, 0x00]);
+        ip.write_u16::<BigEndian>(ip_len as u16)?;
+        ip.write_u16::<BigEndian>(self.seq as u16)?;
+        // Don't fragment
+        ip.write_u16::<BigEndian>(0x4000)?;
+        // TTL, UDP
+        ip.extend_from_slice(&[64, 17]);
+        ip.write_u16::<BigEndian>(0)?;
+        ip.extend_from_slice(&[127, 0, 0, 1, 127, 0, 0, 1]);
+        let checksum = ipv4_checksum(&ip);
+        ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+        ip.write_u16::<BigEndian>(ZEP_PORT)?;
+        ip.write_u16::<BigEndian>(ZEP_PORT)?;
+        ip.write_u16::<BigEndian>(udp_len as u16)?;
+        // No UDP checksum
+        ip.write_u16::<BigEndian>(0)?;
+        ip.extend_from_slice(&zep);
+
+        self.out.write_u32::<LittleEndian>(secs as u32)?;
+        self.out.write_u32::<LittleEndian>(micros as u32)?;
+        self.out.write_u32::<LittleEndian>(ip.len() as u32)?;
+        self.out.write_u32::<LittleEndian>(ip.len() as u32)?;
+        self.out.write_all(&ip)?;
+        self.out.flush()?;
+        Ok(true)
+    }
+    /// Writes AF data frames, returns `false` for anything else, bodies that don't parse
+    /// and frames `write_incoming` or `write_outgoing` skip
+    pub fn write_record(&mut self, record: &Record) -> io::Result<bool> {
+        let cmd = record.to_cmd();
+        match (record.typ, record.subsys, record.cmd_id) {
+            (Type::AREQ, Subsys::AF, <IncomingMsg as AreqIn>::CMD_ID) => match cmd.parse() {
+                Ok(msg) => self.write_incoming(record.timestamp, &msg),
+                Err(_) => Ok(false),
+            },
+            (Type::SREQ, Subsys::AF, <DataRequest as Sreq>::CMD_ID) => match cmd.parse() {
+                Ok(req) => self.write_outgoing(record.timestamp, &req),
+                Err(_) => Ok(false),
+            },
+            _ => Ok(false),
+        }
+    }
+}
+/// Live export, for use as a `capture::Capture`
+impl<W: Write + Send> RecordSink for PcapWriter<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        self.write_record(record).map(|_| ())
+    }
+}
+
+/// Convert a stored capture, returns the number of packets written
+pub fn export<P, Q>(capture: P, pcap: Q) -> io::Result<usize>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let records = capture::read(capture)?;
+    let mut writer = PcapWriter::new(io::BufWriter::new(File::create(pcap)?))?;
+    let mut count = 0;
+    for record in &records {
+        if writer.write_record(record)? {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Direction;
+    use crate::cmd::sys;
+    use crate::cmd::types::ShortAddr;
+    use crate::znp_codec::ZnpCmd;
+
+    /// Packets of a pcap file, IPv4 headers first
+    fn packets(pcap: &[u8]) -> Vec<&[u8]> {
+        assert_eq!(pcap[..4], 0xA1B2_C3D4u32.to_le_bytes());
+        assert_eq!(pcap[20..24], LINKTYPE_IPV4.to_le_bytes());
+        let mut rest = &pcap[24..];
+        let mut packets = vec![];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes([rest[8], rest[9], rest[10], rest[11]]) as usize;
+            packets.push(&rest[16..16 + len]);
+            rest = &rest[16 + len..];
+        }
+        packets
+    }
+
+    #[test]
+    fn export_writes_af_frames_only() {
+        let msg = IncomingMsg {
+            group: 0,
+            cluster: 0x0006,
+            addr: ShortAddr(0x1234),
+            src_ep: 1,
+            dest_ep: 1,
+            was_broadcast: false,
+            link_quality: 0x80,
+            security_used: false,
+            timestamp: 0,
+            tr_seq: 7,
+            data: vec![0x18, 0x07, 0x0B, 0x01, 0x00],
+        };
+        let req = DataRequest {
+            dst_addr: ShortAddr(0x1234),
+            dst_ep: 1,
+            src_ep: 1,
+            cluster: 0x0006,
+            trans_id: 8,
+            options: 0,
+            radius: 30,
+            data: vec![0x01, 0x08, 0x01],
+        };
+        let incoming = ZnpCmd::from_payload(
+            Type::AREQ,
+            Subsys::AF,
+            <IncomingMsg as AreqIn>::CMD_ID,
+            &msg,
+        )
+        .unwrap();
+        let records = [
+            Record::new(Direction::Out, &sys::Ping.frame()),
+            Record::new(Direction::In, &incoming),
+            Record::new(Direction::Out, &req.frame()),
+        ];
+        let dir = std::env::temp_dir();
+        let capture = dir.join(format!("znp-rs-export-{}.jsonl", std::process::id()));
+        let out = dir.join(format!("znp-rs-export-{}.pcap", std::process::id()));
+        let lines: Vec<String> = records
+            .iter()
+            .map(|record| serde_json::to_string(record).unwrap())
+            .collect();
+        std::fs::write(&capture, lines.join("\n")).unwrap();
+
+        let count = export(&capture, &out).unwrap();
+        let pcap = std::fs::read(&out).unwrap();
+        std::fs::remove_file(&capture).unwrap();
+        std::fs::remove_file(&out).unwrap();
+        assert_eq!(count, 2);
+        let packets = packets(&pcap);
+        assert_eq!(packets.len(), 2);
+        for (packet, (src, dst, payload)) in packets
+            .iter()
+            .zip(&[(0x1234, COORD, &msg.data), (COORD, 0x1234, &req.data)])
+        {
+            assert_eq!(ipv4_checksum(&packet[..20]), 0);
+            let zep = &packet[28..];
+            assert_eq!(&zep[..2], b"EX");
+            assert_eq!(zep[31] as usize, zep.len() - 32);
+            let mac = &zep[32..];
+            let (frame, crc) = mac.split_at(mac.len() - 2);
+            assert_eq!(fcs(frame).to_le_bytes(), crc);
+            assert_eq!(frame[5..7], u16::to_le_bytes(*dst));
+            assert_eq!(frame[7..9], u16::to_le_bytes(*src));
+            // APS cluster after the MAC, NWK and APS control and endpoint
+            assert_eq!(frame[19..21], 0x0006u16.to_le_bytes());
+            assert!(frame.ends_with(payload));
+        }
+    }
+
+    #[test]
+    fn skips_frames_longer_than_a_psdu() {
+        let mut req = DataRequest {
+            dst_addr: ShortAddr(0x1234),
+            dst_ep: 1,
+            src_ep: 1,
+            cluster: 0x0006,
+            trans_id: 8,
+            options: 0,
+            radius: 30,
+            data: vec![0; MAX_PSDU],
+        };
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        assert!(!writer.write_outgoing(0, &req).unwrap());
+        assert!(packets(&writer.out).is_empty());
+        // MAC, NWK and APS headers plus the FCS
+        req.data.truncate(MAX_PSDU - 27);
+        assert!(writer.write_outgoing(0, &req).unwrap());
+        let packets = packets(&writer.out);
+        assert_eq!(packets[0][28 + 31] as usize, MAX_PSDU);
+    }
+}
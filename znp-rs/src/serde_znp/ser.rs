@@ -149,7 +149,7 @@ where
 
     #[inline]
     fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
-        unimplemented!();
+        // Fixed size arrays, no length prefix
         Ok(self)
     }
 
@@ -271,7 +271,6 @@ where
     where
         V: serde::Serialize,
     {
-        unimplemented!();
         value.serialize(&mut **self)
     }
 
@@ -0,0 +1,650 @@
+//! Simulated Z-Stack coordinator speaking ZNP over an in-process pipe,
+//! for running `znp::Sender` and everything above it without a dongle.
+//! Unix only, the pipe is a `UnixStream` pair.
+use crate::areq::{AreqIn, AreqOut};
+use crate::cmd::types::{IEEEAddr, ShortAddr};
+use crate::cmd::{af, sys, util, zb, zdo};
+use crate::network::{DEFAULT_KEY, STARTOPT_CLEAR_STATE};
+use crate::sreq::Sreq;
+use crate::transport::Transport;
+use crate::znp_codec::{Subsys, Type, ZnpCmd, ZnpCodec};
+use bytes::BytesMut;
+use futures_util::{future, stream, SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+
+/// RPC error codes carried by the SRSP to an unhandled SREQ
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum RpcError {
+    Subsystem = 0x01,
+    CommandId = 0x02,
+    Parameter = 0x03,
+}
+
+/// Coordinator state the scripted handlers read and mutate
+#[derive(Debug)]
+pub struct Device {
+    pub ieee_addr: IEEEAddr,
+    pub short_addr: ShortAddr,
+    /// `ZbCoord` once started
+    pub dev_state: zdo::DeviceState,
+    pub channel: u8,
+    pub pan_id: u16,
+    pub ext_pan_id: u64,
+    /// Registered AF endpoints, forgotten on reset
+    pub endpoints: Vec<af::Register>,
+    /// OSAL NV items, legacy ones under `sys::NvId::legacy`
+    pub nv: HashMap<sys::NvId, Vec<u8>>,
+    /// ZB_READ_CONFIGURATION values
+    pub config: HashMap<zb::ConfigId, Vec<u8>>,
+    indications: Vec<ZnpCmd>,
+    /// Network state was cleared, the next start forms a new network
+    cleared: bool,
+    /// xorshift state behind SYS_RANDOM, repeatable from run to run
+    random: u32,
+}
+impl Default for Device {
+    fn default() -> Self {
+        use zb::ConfigId;
+        // Freshly flashed, nothing in NV yet
+        let nv = HashMap::new();
+        let pan_id: u16 = 0x1A62;
+        let ext_pan_id: u64 = 0xDDDD_DDDD_DDDD_DDDD;
+        let mut config = HashMap::new();
+        config.insert(ConfigId::StartupOption, vec![0x00]);
+        config.insert(ConfigId::Panid, pan_id.to_le_bytes().to_vec());
+        config.insert(ConfigId::ExtendedPanId, ext_pan_id.to_le_bytes().to_vec());
+        // CH11
+        config.insert(ConfigId::Chanlist, vec![0x00, 0x08, 0x00, 0x00]);
+        config.insert(ConfigId::LogicalType, vec![0x00]);
+        config.insert(ConfigId::Precfgkey, DEFAULT_KEY.to_vec());
+        config.insert(ConfigId::PrecfgkeysEnable, vec![0x00]);
+        config.insert(ConfigId::ZdoDirectCb, vec![0x01]);
+        Device {
+            ieee_addr: IEEEAddr(0x0012_4B00_0001_5A3E),
+            short_addr: ShortAddr(0x0000),
+            dev_state: zdo::DeviceState::Hold,
+            channel: 11,
+            pan_id,
+            ext_pan_id,
+            endpoints: Vec::new(),
+            nv,
+            config,
+            indications: Vec::new(),
+            cleared: false,
+            random: 0x2545_F491,
+        }
+    }
+}
+/// Most an NV read returns in one SRSP
+const NV_READ_MAX: usize = 240;
+
+impl Device {
+    /// Queue an AREQ to go out right after the SRSP being built
+    pub fn indicate<A: AreqIn + Serialize>(&mut self, areq: &A) {
+        self.indications.push(areq_frame(areq));
+    }
+    fn nv_read(&self, id: sys::NvId, offset: usize, len: usize) -> sys::NvReadRsp {
+        match self.nv.get(&id) {
+            Some(item) if offset <= item.len() => {
+                let end = item.len().min(offset + len.min(NV_READ_MAX));
+                sys::NvReadRsp {
+                    status: 0,
+                    value: item[offset..end].to_vec(),
+                }
+            }
+            _ => sys::NvReadRsp {
+                status: 1,
+                value: Vec::new(),
+            },
+        }
+    }
+    fn nv_write(&mut self, id: sys::NvId, offset: usize, value: &[u8]) -> u8 {
+        match self.nv.get_mut(&id) {
+            Some(item) if offset + value.len() <= item.len() => {
+                item[offset..offset + value.len()].copy_from_slice(value);
+                0
+            }
+            Some(_) => sys::NV_BAD_ITEM_LEN,
+            None => sys::NV_OPER_FAILED,
+        }
+    }
+    /// Status 0 if it existed already, like SYS_OSAL_NV_ITEM_INIT
+    fn nv_create(&mut self, id: sys::NvId, len: usize, init: &[u8]) -> u8 {
+        if self.nv.contains_key(&id) {
+            return 0;
+        }
+        let mut item = vec![0xFF; len];
+        let init = &init[..init.len().min(len)];
+        item[..init.len()].copy_from_slice(init);
+        self.nv.insert(id, item);
+        sys::NV_ITEM_UNINIT
+    }
+    fn nv_delete(&mut self, id: sys::NvId, len: Option<usize>) -> u8 {
+        match self.nv.get(&id) {
+            Some(item) if len.map_or(true, |len| len == item.len()) => {
+                self.nv.remove(&id);
+                0
+            }
+            Some(_) => sys::NV_BAD_ITEM_LEN,
+            None => sys::NV_ITEM_UNINIT,
+        }
+    }
+    fn nv_len(&self, id: sys::NvId) -> usize {
+        self.nv.get(&id).map_or(0, Vec::len)
+    }
+    /// STARTUP_OPTION clear state: the next start forms from the configured parameters
+    fn clear_state_if_asked(&mut self) {
+        use zb::ConfigId;
+        let option = match self.config.get(&ConfigId::StartupOption).map(Vec::as_slice) {
+            Some(&[option, ..]) if option & STARTOPT_CLEAR_STATE != 0 => option,
+            _ => return,
+        };
+        self.config.insert(
+            ConfigId::StartupOption,
+            vec![option & !STARTOPT_CLEAR_STATE],
+        );
+        self.cleared = true;
+        if let Some(&[a, b]) = self.config.get(&ConfigId::Panid).map(Vec::as_slice) {
+            self.pan_id = u16::from_le_bytes([a, b]);
+        }
+        if let Some(ext) = self
+            .config
+            .get(&ConfigId::ExtendedPanId)
+            .filter(|e| e.len() == 8)
+        {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(ext);
+            self.ext_pan_id = u64::from_le_bytes(bytes);
+        }
+        if let Some(&[a, b, c, d]) = self.config.get(&ConfigId::Chanlist).map(Vec::as_slice) {
+            let mask = u32::from_le_bytes([a, b, c, d]);
+            if let Some(channel) = (11..=26).find(|ch| mask & 1 << ch != 0) {
+                self.channel = channel;
+            }
+        }
+    }
+    fn device_info(&self, param: &zb::ZbDeviceInfoProp) -> u64 {
+        use zb::ZbDeviceInfoProp as Prop;
+        match param {
+            Prop::DevState => self.dev_state as u64,
+            Prop::IeeeAddr => self.ieee_addr.0,
+            Prop::ShortAddr => self.short_addr.0.into(),
+            Prop::ParentShortAddr | Prop::ParentIeeeAddr => 0,
+            Prop::Channel => self.channel.into(),
+            Prop::PanId => self.pan_id.into(),
+            Prop::ExtPanId => self.ext_pan_id,
+        }
+    }
+}
+
+fn areq_frame<A: AreqIn + Serialize>(areq: &A) -> ZnpCmd {
+    ZnpCmd::from_payload(Type::AREQ, A::SUBSYS, A::CMD_ID, areq).expect("AREQ doesn't serialize")
+}
+fn rpc_error(cmd: &ZnpCmd, err: RpcError) -> ZnpCmd {
+    let cmd0 = cmd.typ() as u8 + cmd.subsys() as u8;
+    let body = BytesMut::from(&[err as u8, cmd0, cmd.cmd_id()][..]);
+    ZnpCmd::new(Type::SRSP, Subsys::Reserved, 0x00, body)
+}
+
+/// Returns the SRSP, `None` for AREQs
+type Handler = Box<dyn FnMut(&mut Device, ZnpCmd) -> Option<ZnpCmd> + Send>;
+
+pub struct Simulator {
+    device: Device,
+    handlers: HashMap<(Subsys, u8), Handler>,
+}
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Simulator {
+    /// Nothing scripted, every SREQ gets an RPC error SRSP
+    pub fn empty() -> Self {
+        Simulator {
+            device: Default::default(),
+            handlers: HashMap::new(),
+        }
+    }
+    /// Answers like a freshly flashed coordinator for everything `init_coord` uses
+    pub fn new() -> Self {
+        let mut sim = Self::empty();
+        sim.on(|_, _: sys::StartTimer| sys::StartTimerRsp { status: 0 })
+            .on(|_, _: sys::Ping| sys::PingRsp {
+                capabilities: 0x0179,
+            })
+            .on(|_, _: sys::Version| sys::VersionRsp {
+                transport_rev: 2,
+                product_id: 1,
+                major_rel: 2,
+                minor_rel: 7,
+                maint_rel: 1,
+            })
+            .on(|dev, _: sys::GetExtAddr| sys::GetExtAddrRsp {
+                ext_addr: dev.ieee_addr,
+            })
+            .on(|dev, _: sys::Random| {
+                dev.random ^= dev.random << 13;
+                dev.random ^= dev.random >> 17;
+                dev.random ^= dev.random << 5;
+                sys::RandomRsp {
+                    value: dev.random as u16,
+                }
+            })
+            .on(|dev, req: sys::NvRead| {
+                dev.nv_read(sys::NvId::legacy(req.id), req.offset.into(), NV_READ_MAX)
+            })
+            .on(|dev, req: sys::NvReadExt| {
+                dev.nv_read(sys::NvId::legacy(req.id), req.offset.into(), NV_READ_MAX)
+            })
+            .on(|dev, req: sys::NvWrite| sys::NvStatusRsp {
+                status: dev.nv_write(sys::NvId::legacy(req.id), req.offset.into(), &req.value),
+            })
+            .on(|dev, req: sys::NvWriteExt| sys::NvStatusRsp {
+                status: dev.nv_write(sys::NvId::legacy(req.id), req.offset.into(), &req.value),
+            })
+            .on(|dev, req: sys::NvItemInit| sys::NvStatusRsp {
+                status: dev.nv_create(
+                    sys::NvId::legacy(req.id),
+                    req.item_len.into(),
+                    &req.init_data,
+                ),
+            })
+            .on(|dev, req: sys::NvDelete| sys::NvStatusRsp {
+                status: dev.nv_delete(sys::NvId::legacy(req.id), Some(req.item_len.into())),
+            })
+            .on(|dev, req: sys::NvLength| sys::NvLengthRsp {
+                length: dev.nv_len(sys::NvId::legacy(req.id)) as u16,
+            })
+            .on(|dev, req: sys::SysNvCreate| sys::NvStatusRsp {
+                status: dev.nv_create(req.id, req.length as usize, &[]),
+            })
+            .on(|dev, req: sys::SysNvDelete| sys::NvStatusRsp {
+                status: dev.nv_delete(req.id, None),
+            })
+            .on(|dev, req: sys::SysNvLength| sys::SysNvLengthRsp {
+                length: dev.nv_len(req.id) as u32,
+            })
+            .on(|dev, req: sys::SysNvRead| dev.nv_read(req.id, req.offset.into(), req.len.into()))
+            .on(|dev, req: sys::SysNvWrite| sys::NvStatusRsp {
+                status: dev.nv_write(req.id, req.offset.into(), &req.value),
+            })
+            .on(|dev, req: sys::SysNvUpdate| {
+                dev.nv.insert(req.id, req.value);
+                sys::NvStatusRsp { status: 0 }
+            })
+            .on_areq(|dev, _: sys::ResetReq| {
+                dev.endpoints.clear();
+                dev.dev_state = zdo::DeviceState::Hold;
+                dev.clear_state_if_asked();
+                dev.indicate(&sys::Reset {
+                    reason: sys::ResetReason::External,
+                    transport_rev: 2,
+                    product_id: 1,
+                    major_rel: 2,
+                    minor_rel: 7,
+                    hw_rev: 1,
+                });
+            })
+            .on(|_, _: util::UtilLedControl| util::UtilLedControlRsp { status: 0 })
+            .on(|dev, req: zb::ZbGetDeviceInfoReq| zb::ZbGetDeviceInfoRsp {
+                value: dev.device_info(&req.param).to_le_bytes(),
+                param: req.param,
+            })
+            .on(|dev, req: zb::ReadConfig| match dev.config.get(&req.id) {
+                Some(value) => zb::ReadConfigRsp {
+                    status: 0,
+                    id: req.id,
+                    value: value.clone(),
+                },
+                None => zb::ReadConfigRsp {
+                    status: 1,
+                    id: req.id,
+                    value: Vec::new(),
+                },
+            })
+            .on(|dev, req: zb::WriteConfig| {
+                dev.config.insert(req.id, req.value);
+                zb::WriteConfigRsp { status: 0 }
+            })
+            .on(|dev, _: zdo::StartupFromApp| {
+                if dev.dev_state != zdo::DeviceState::ZbCoord {
+                    for &state in &[zdo::DeviceState::CoordStarting, zdo::DeviceState::ZbCoord] {
+                        dev.dev_state = state;
+                        dev.indicate(&zdo::StateChange { state });
+                    }
+                }
+                let status = if dev.cleared {
+                    zdo::StartupFromAppStatus::New
+                } else {
+                    zdo::StartupFromAppStatus::Restored
+                };
+                dev.cleared = false;
+                zdo::StartupFromAppRsp { status }
+            })
+            .on(|dev, req: zdo::NodeDescReq| {
+                if req.query_addr.0 == dev.short_addr.0 {
+                    dev.indicate(&zdo::NodeDescRsp {
+                        src_addr: dev.short_addr,
+                        status: 0,
+                        query_addr: dev.short_addr,
+                        // Coordinator
+                        field1: 0x00,
+                        // 2.4GHz
+                        field2: 0x40,
+                        mac_capabilities: 0x8F,
+                        manuf_code: 0x0000,
+                        max_buffer_size: 0x50,
+                        max_in_transfer_size: 0x00A0,
+                        server_mask: 0x0041,
+                        max_out_transfer_size: 0x00A0,
+                        descriptor_capabilities: 0x00,
+                    });
+                }
+                zdo::NodeDescReqRsp { status: 0 }
+            })
+            .on(|dev, req: zdo::PowerDescReq| {
+                if req.query_addr.0 == dev.short_addr.0 {
+                    dev.indicate(&zdo::PowerDescRsp {
+                        src_addr: dev.short_addr,
+                        status: 0,
+                        query_addr: dev.short_addr,
+                        // Receiver on when idle, mains
+                        field1: 0x10,
+                        // Mains, 100%
+                        field2: 0xC1,
+                    });
+                }
+                zdo::PowerDescReqRsp { status: 0 }
+            })
+            .on(|dev, req: zdo::ActiveEpReq| {
+                if req.query_addr.0 == dev.short_addr.0 {
+                    dev.indicate(&zdo::ActiveEpRsp {
+                        src_addr: dev.short_addr,
+                        status: 0,
+                        query_addr: dev.short_addr,
+                        active_eps: dev.endpoints.iter().map(|ep| ep.ep).collect(),
+                    });
+                }
+                zdo::ActiveEpReqRsp { status: 0 }
+            })
+            .on(|dev, req: zdo::SimpleDescReq| {
+                if req.query_addr.0 == dev.short_addr.0 {
+                    let ep = dev.endpoints.iter().find(|ep| ep.ep == req.endpoint.0);
+                    let rsp = match ep {
+                        Some(ep) => zdo::SimpleDescRsp {
+                            src_addr: dev.short_addr,
+                            status: 0,
+                            query_addr: dev.short_addr,
+                            len: (8 + 2 * (ep.in_clusters.len() + ep.out_clusters.len())) as u8,
+                            endpoint: req.endpoint,
+                            profile_id: ep.app_prof,
+                            device_id: ep.dev_type,
+                            device_version: ep.dev_ver,
+                            input_clusters: ep.in_clusters.clone(),
+                            output_clusters: ep.out_clusters.clone(),
+                        },
+                        // NOT_ACTIVE
+                        None => zdo::SimpleDescRsp {
+                            src_addr: dev.short_addr,
+                            status: 0x83,
+                            query_addr: dev.short_addr,
+                            len: 0,
+                            endpoint: req.endpoint,
+                            profile_id: 0,
+                            device_id: 0,
+                            device_version: 0,
+                            input_clusters: Vec::new(),
+                            output_clusters: Vec::new(),
+                        },
+                    };
+                    dev.indicate(&rsp);
+                }
+                zdo::SimpleDescReqRsp { status: 0 }
+            })
+            .on(|dev, req: zdo::ComplexDescReq| {
+                if req.query_addr.0 == dev.short_addr.0 {
+                    // NOT_SUPPORTED
+                    dev.indicate(&zdo::ComplexDescRsp {
+                        src_addr: dev.short_addr,
+                        status: 0x84,
+                        query_addr: dev.short_addr,
+                        complex_descriptor: Vec::new(),
+                    });
+                }
+                zdo::ComplexDescReqRsp { status: 0 }
+            })
+            .on(|dev, req: zdo::MgmtPermitJoinReq| {
+                dev.indicate(&zdo::MgmtPermitJoinInd {
+                    duration: req.duration,
+                });
+                zdo::MgmtPermitJoinReqRsp { status: 0 }
+            })
+            .on(|dev, req: af::Register| {
+                if dev.endpoints.iter().any(|ep| ep.ep == req.ep) {
+                    // Already Exists
+                    af::RegisterRsp { status: 0xB8 }
+                } else {
+                    dev.endpoints.push(req);
+                    af::RegisterRsp { status: 0 }
+                }
+            });
+        sim
+    }
+    pub fn device_mut(&mut self) -> &mut Device {
+        &mut self.device
+    }
+    /// Script the SRSP to a typed SREQ, replacing any previous handler
+    pub fn on<S, F>(&mut self, mut f: F) -> &mut Self
+    where
+        S: Sreq + DeserializeOwned,
+        S::Srsp: Serialize,
+        F: FnMut(&mut Device, S) -> S::Srsp + Send + 'static,
+    {
+        self.on_raw(S::SUBSYS, S::CMD_ID, move |dev, cmd| {
+            let srsp = match cmd.parse() {
+                Ok(req) => f(dev, req),
+                Err(_) => return Some(rpc_error(&cmd, RpcError::Parameter)),
+            };
+            ZnpCmd::from_payload(Type::SRSP, S::SUBSYS, S::CMD_ID, &srsp).ok()
+        })
+    }
+    /// React to an AREQ from the host
+    pub fn on_areq<A, F>(&mut self, mut f: F) -> &mut Self
+    where
+        A: AreqOut + DeserializeOwned,
+        F: FnMut(&mut Device, A) + Send + 'static,
+    {
+        self.on_raw(A::SUBSYS, A::CMD_ID, move |dev, cmd| {
+            if let Ok(req) = cmd.parse() {
+                f(dev, req);
+            }
+            None
+        })
+    }
+    /// Handle frames by hand. Must return the SRSP for SREQs.
+    pub fn on_raw<F>(&mut self, subsys: Subsys, cmd_id: u8, f: F) -> &mut Self
+    where
+        F: FnMut(&mut Device, ZnpCmd) -> Option<ZnpCmd> + Send + 'static,
+    {
+        self.handlers.insert((subsys, cmd_id), Box::new(f));
+        self
+    }
+    /// Start answering on one end of a socket pair, the other end is for `znp::Sender::new`
+    pub fn spawn(self) -> io::Result<(UnixStream, SimHandle)> {
+        let (host, io) = UnixStream::pair()?;
+        Ok((host, self.serve(io)))
+    }
+    /// Start answering on an already connected stream, e.g. one a fake TCP bridge accepted
+    pub fn serve<T: Transport + 'static>(self, io: T) -> SimHandle {
+        let device = Arc::new(Mutex::new(self.device));
+        let (inject_tx, inject_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(self.handlers, device.clone(), io, inject_rx));
+        SimHandle { device, inject_tx }
+    }
+}
+
+/// Control over a running `Simulator`
+#[derive(Clone)]
+pub struct SimHandle {
+    device: Arc<Mutex<Device>>,
+    /// `None` hangs up
+    inject_tx: mpsc::UnboundedSender<Option<ZnpCmd>>,
+}
+impl SimHandle {
+    pub fn device(&self) -> MutexGuard<'_, Device> {
+        self.device.lock().unwrap()
+    }
+    /// Send an unsolicited AREQ, like a device announcing itself
+    pub fn inject<A: AreqIn + Serialize>(&self, areq: &A) -> io::Result<()> {
+        self.inject_raw(areq_frame(areq))
+    }
+    pub fn inject_raw(&self, cmd: ZnpCmd) -> io::Result<()> {
+        self.inject_tx
+            .send(Some(cmd))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "simulator stopped"))
+    }
+    /// Close the connection, like pulling the stick out
+    pub fn hang_up(&self) {
+        // Stopped already is just as good
+        let _ = self.inject_tx.send(None);
+    }
+}
+
+enum Event {
+    Host(ZnpCmd),
+    Inject(Option<ZnpCmd>),
+    Closed,
+}
+async fn run<T: Transport>(
+    mut handlers: HashMap<(Subsys, u8), Handler>,
+    device: Arc<Mutex<Device>>,
+    io: T,
+    inject_rx: mpsc::UnboundedReceiver<Option<ZnpCmd>>,
+) {
+    let (mut tx, rx) = Framed::new(io, ZnpCodec::new()).split();
+    let host = rx
+        .take_while(|frame| future::ready(frame.is_ok()))
+        .map(|frame| Event::Host(frame.unwrap()))
+        .chain(stream::once(future::ready(Event::Closed)));
+    let mut events = stream::select(host, inject_rx.map(Event::Inject));
+    while let Some(event) = events.next().await {
+        let out = match event {
+            Event::Closed | Event::Inject(None) => break,
+            Event::Inject(Some(cmd)) => vec![cmd],
+            Event::Host(cmd) => {
+                let mut dev = device.lock().unwrap();
+                let is_sreq = cmd.typ() == Type::SREQ;
+                let srsp = match handlers.get_mut(&(cmd.subsys(), cmd.cmd_id())) {
+                    Some(handler) => handler(&mut dev, cmd),
+                    None if is_sreq => {
+                        let known = handlers.keys().any(|&(subsys, _)| subsys == cmd.subsys());
+                        let err = if known {
+                            RpcError::CommandId
+                        } else {
+                            RpcError::Subsystem
+                        };
+                        Some(rpc_error(&cmd, err))
+                    }
+                    None => None,
+                };
+                // SRSP always goes first, the AREQs it caused follow
+                srsp.into_iter()
+                    .chain(dev.indications.drain(..))
+                    .collect::<Vec<_>>()
+            }
+        };
+        for frame in out {
+            if tx.send(frame).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{AreqFilter, Event};
+    use crate::cmd::Areq;
+    use crate::init_coord::{ZNP_CONFIGURED, ZNP_HAS_CONFIGURED};
+    use crate::znp::{Sender, SreqError};
+
+    #[tokio::test]
+    async fn sreq_round_trip() {
+        let mut sim = Simulator::new();
+        sim.device_mut().ieee_addr = IEEEAddr(0x0011_2233_4455_6677);
+        let (io, handle) = sim.spawn().unwrap();
+        let znp = Sender::new(io);
+        let rsp = znp.sreq(sys::GetExtAddr).await.unwrap();
+        assert_eq!(rsp.ext_addr.0, 0x0011_2233_4455_6677);
+        let init = sys::NvItemInit {
+            id: ZNP_HAS_CONFIGURED,
+            item_len: 1,
+            init_data: vec![ZNP_CONFIGURED],
+        };
+        assert_eq!(znp.sreq(init).await.unwrap().status, sys::NV_ITEM_UNINIT);
+        let write = sys::NvWrite {
+            id: ZNP_HAS_CONFIGURED,
+            offset: 0,
+            value: vec![0x00],
+        };
+        assert_eq!(znp.sreq(write).await.unwrap().status, 0);
+        let id = sys::NvId::legacy(ZNP_HAS_CONFIGURED);
+        assert_eq!(handle.device().nv[&id], vec![0x00]);
+    }
+
+    #[tokio::test]
+    async fn inject_reaches_subscribers() {
+        let (io, handle) = Simulator::new().spawn().unwrap();
+        let znp = Sender::new(io);
+        let mut sub = znp.subscribe(AreqFilter {
+            subsys: Some(Subsys::ZDO),
+            cmd_id: Some(<zdo::EndDevAnnce as AreqIn>::CMD_ID),
+            ..Default::default()
+        });
+        handle
+            .inject(&zdo::EndDevAnnce {
+                src_addr: ShortAddr(0x1234),
+                nwk_addr: ShortAddr(0x1234),
+                ieee_addr: IEEEAddr(0x0011_2233_4455_6677),
+                capabilities: 0x80,
+            })
+            .unwrap();
+        match sub.recv().await {
+            Some(Event::Areq(areq)) => match &*areq {
+                Areq::Zdo(zdo::In::EndDevAnnce(annce)) => {
+                    assert_eq!(annce.nwk_addr.0, 0x1234);
+                    assert_eq!(annce.ieee_addr.0, 0x0011_2233_4455_6677);
+                }
+                areq => panic!("unexpected {:?}", areq),
+            },
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn unhandled_sreq_gets_rpc_error() {
+        let mut sim = Simulator::empty();
+        sim.on(|_, _: sys::Ping| sys::PingRsp { capabilities: 0 });
+        let (io, _handle) = sim.spawn().unwrap();
+        let znp = Sender::new(io);
+        assert!(znp.sreq(sys::Ping).await.is_ok());
+        match znp.sreq(sys::Version).await {
+            Err(SreqError::Rpc(code)) => assert_eq!(code, RpcError::CommandId as u8),
+            res => panic!("unexpected {:?}", res),
+        }
+        match znp.sreq(zdo::StartupFromApp { delay: 0 }).await {
+            Err(SreqError::Rpc(code)) => assert_eq!(code, RpcError::Subsystem as u8),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -1,13 +1,21 @@
+use crate::areq::AreqIn;
 use crate::cmd::error::Result;
 use crate::znp_codec::{Subsys, Type, ZnpCmd};
 use bytes::{buf::BufMutExt, BytesMut};
 use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+
 pub trait Sreq: Serialize {
     type Srsp: DeserializeOwned;
     const SUBSYS: Subsys;
     const CMD_ID: u8;
     /// Serialized params size; <= 250
     const MAX_SIZE: usize;
+    /// How long to wait for the SRSP, unless overridden per call
+    const TIMEOUT: Duration = DEFAULT_TIMEOUT;
+    /// Sending it twice does no harm, so it is retried on timeout per `znp::RetryPolicy`
+    const IDEMPOTENT: bool = false;
     fn frame(&self) -> ZnpCmd {
         let mut body = BytesMut::with_capacity(Self::MAX_SIZE);
         let writer = (&mut body).writer();
@@ -18,3 +26,15 @@ pub trait Sreq: Serialize {
         res.parse().map_err(From::from)
     }
 }
+
+/// SREQ whose SRSP only says whether it was accepted,
+/// the actual answer follows as an AREQ
+pub trait WithCallback: Sreq {
+    type Callback: AreqIn;
+    /// How long to wait for the callback once the SRSP came
+    const CALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Non-zero means no callback will follow
+    fn status(srsp: &Self::Srsp) -> u8;
+    /// Whether `callback` answers this request
+    fn matches(&self, callback: &Self::Callback) -> bool;
+}
@@ -0,0 +1,358 @@
+use crate::cmd::sys;
+use crate::sreq::Sreq;
+use crate::znp_codec::{Type, ZnpCodec};
+use futures_util::{SinkExt, StreamExt};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, timeout};
+use tokio_serial::{Serial, SerialPort, SerialPortSettings};
+use tokio_util::codec::Framed;
+use tracing::{debug, info, warn};
+
+pub use tokio_serial::FlowControl;
+
+/// Makes the TI serial bootloader jump to ZNP right away
+pub const BOOTLOADER_SKIP_BYTE: u8 = 0xEF;
+/// How long ZNP takes to boot once the bootloader let go
+const BOOTLOADER_SKIP_WAIT: Duration = Duration::from_secs(1);
+
+/// Any duplex byte stream a coordinator can be reached over:
+/// serial port, TCP or Unix socket, in-memory pipe
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// Where the coordinator is plugged in
+#[derive(Debug, Clone, PartialEq)]
+pub enum Port {
+    /// Local serial device, `/dev/ttyACM0`
+    Serial(PathBuf),
+    /// Serial bridge such as ser2net or socat, `tcp://raspberrypi:6638`
+    Tcp(String),
+    /// In-process `sim::Simulator`, `sim://`, Unix only
+    Simulated,
+    /// Fake coordinator playing back a `capture` file, `replay:///tmp/capture.jsonl`, Unix only
+    Replay(PathBuf),
+}
+impl FromStr for Port {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Self> {
+        if s == "sim://" {
+            Ok(Port::Simulated)
+        } else if let Some(path) = s.strip_prefix("replay://") {
+            Ok(Port::Replay(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("tcp://") {
+            if addr.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "tcp:// needs host:port",
+                ));
+            }
+            Ok(Port::Tcp(addr.to_owned()))
+        } else {
+            Ok(Port::Serial(PathBuf::from(s)))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// Give up on a single connection attempt after this long
+    pub connect_timeout: Duration,
+    /// Attempts before giving up, `None` to retry forever
+    pub attempts: Option<u32>,
+    /// Delay after the first failed attempt, doubled after each next one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Only used by `Port::Serial`
+    pub serial: SerialConfig,
+}
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            connect_timeout: Duration::from_secs(5),
+            attempts: Some(5),
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            serial: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BaudRate {
+    Fixed(u32),
+    /// Try each rate in turn until one answers SYS_PING
+    Auto(Vec<u32>),
+}
+
+/// Line settings, always 8-N-1
+#[derive(Debug, Clone)]
+pub struct SerialConfig {
+    pub baud_rate: BaudRate,
+    /// Some CC2652/CC1352 boards need RTS/CTS
+    pub flow_control: FlowControl,
+    /// Levels DTR and RTS are driven to right after opening, `None` leaves them as they are.
+    /// Boards wiring them to RESET or the bootloader pin need both low.
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+    /// How long each candidate rate gets to answer when auto-detecting
+    pub probe_timeout: Duration,
+    /// Send `BOOTLOADER_SKIP_BYTE` at each candidate rate before probing it, since a stick
+    /// still in the bootloader answers nothing. Set from `znp::Options::skip_bootloader`.
+    pub skip_bootloader: bool,
+}
+impl SerialConfig {
+    /// Candidates for `BaudRate::Auto`, most common first
+    pub const COMMON_RATES: [u32; 5] = [115_200, 230_400, 460_800, 57_600, 38_400];
+
+    pub fn auto() -> Self {
+        SerialConfig {
+            baud_rate: BaudRate::Auto(Self::COMMON_RATES.to_vec()),
+            ..Default::default()
+        }
+    }
+}
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            baud_rate: BaudRate::Fixed(115_200),
+            flow_control: FlowControl::None,
+            dtr: None,
+            rts: None,
+            probe_timeout: Duration::from_millis(500),
+            skip_bootloader: false,
+        }
+    }
+}
+
+/// Opens at the first rate of `BaudRate::Auto` without probing
+pub fn open_serial<P: AsRef<Path>>(path: P, config: &SerialConfig) -> io::Result<Serial> {
+    let baud_rate = match &config.baud_rate {
+        BaudRate::Fixed(rate) => *rate,
+        BaudRate::Auto(rates) => *rates.first().unwrap_or(&115_200),
+    };
+    let sp_settings = SerialPortSettings {
+        baud_rate,
+        flow_control: config.flow_control,
+        ..Default::default() // 8-N-1 is default
+    };
+    let mut sp = Serial::from_path(path, &sp_settings)?;
+    if let Some(level) = config.dtr {
+        sp.write_data_terminal_ready(level)?;
+    }
+    if let Some(level) = config.rts {
+        sp.write_request_to_send(level)?;
+    }
+    Ok(sp)
+}
+
+/// Open, then switch rates until the coordinator answers SYS_PING
+pub async fn detect_serial<P: AsRef<Path>>(path: P, config: &SerialConfig) -> io::Result<Serial> {
+    let rates = match &config.baud_rate {
+        BaudRate::Fixed(rate) => vec![*rate],
+        BaudRate::Auto(rates) => rates.clone(),
+    };
+    let sp = open_serial(path, config)?;
+    let mut framed = Framed::new(sp, ZnpCodec::new());
+    for rate in rates {
+        framed.get_mut().set_baud_rate(rate)?;
+        if config.skip_bootloader {
+            // Not a frame, so it bypasses the codec
+            framed.get_mut().write_all(&[BOOTLOADER_SKIP_BYTE]).await?;
+            delay_for(BOOTLOADER_SKIP_WAIT).await;
+        }
+        if let Ok(Ok(true)) = timeout(config.probe_timeout, ping(&mut framed)).await {
+            info!(baud_rate = rate, "coordinator answered SYS_PING");
+            return Ok(framed.into_inner());
+        }
+        debug!(baud_rate = rate, "no answer to SYS_PING");
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no answer to SYS_PING at any baud rate",
+    ))
+}
+
+/// `false` when the port closed before a valid SRSP came back
+async fn ping(framed: &mut Framed<Serial, ZnpCodec>) -> io::Result<bool> {
+    framed.send(sys::Ping.frame()).await?;
+    while let Some(frame) = framed.next().await {
+        match frame {
+            Ok(frame)
+                if frame.typ() == Type::SRSP
+                    && frame.subsys() == <sys::Ping as Sreq>::SUBSYS
+                    && frame.cmd_id() == <sys::Ping as Sreq>::CMD_ID =>
+            {
+                return Ok(frame.parse::<sys::PingRsp>().is_ok());
+            }
+            // Garbage at the wrong rate, or frames queued from before
+            Ok(_) | Err(_) => continue,
+        }
+    }
+    Ok(false)
+}
+
+async fn open_tcp(addr: &str) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await?;
+    // Frames are tiny and latency-sensitive
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+impl Port {
+    /// Single attempt, bounded by `connect_timeout`
+    pub async fn open(&self, opts: &ConnectOptions) -> io::Result<Box<dyn Transport>> {
+        match self {
+            Port::Serial(path) => match opts.serial.baud_rate {
+                BaudRate::Fixed(_) => Ok(Box::new(open_serial(path, &opts.serial)?)),
+                BaudRate::Auto(_) => Ok(Box::new(detect_serial(path, &opts.serial).await?)),
+            },
+            Port::Tcp(addr) => {
+                let stream = timeout(opts.connect_timeout, open_tcp(addr))
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::TimedOut, "TCP connect timed out")
+                    })??;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            Port::Simulated => {
+                let (io, _sim) = crate::sim::Simulator::new().spawn()?;
+                Ok(Box::new(io))
+            }
+            #[cfg(not(unix))]
+            Port::Simulated => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "sim:// needs Unix domain sockets",
+            )),
+            #[cfg(unix)]
+            Port::Replay(path) => {
+                let records = crate::capture::read(path)?;
+                Ok(Box::new(crate::capture::replay(records)?))
+            }
+            #[cfg(not(unix))]
+            Port::Replay(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "replay:// needs Unix domain sockets",
+            )),
+        }
+    }
+    /// Keep opening with exponential backoff until it works or attempts run out
+    pub async fn connect(&self, opts: &ConnectOptions) -> io::Result<Box<dyn Transport>> {
+        let mut backoff = opts.backoff;
+        let mut attempt = 1;
+        loop {
+            match self.open(opts).await {
+                Ok(io) => return Ok(io),
+                Err(err) => {
+                    let exhausted = match opts.attempts {
+                        Some(max) => attempt >= max,
+                        None => false,
+                    };
+                    if exhausted {
+                        return Err(err);
+                    }
+                    warn!(port = ?self, attempt, error = %err, "couldn't open port");
+                }
+            }
+            delay_for(backoff).await;
+            backoff = std::cmp::min(backoff * 2, opts.max_backoff);
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+
+    fn tcp(listener: &TcpListener) -> Port {
+        Port::Tcp(listener.local_addr().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn tcp_reaches_bridge() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut io = tcp(&listener).open(&Default::default()).await.unwrap();
+        let (mut bridge, _) = listener.accept().await.unwrap();
+        io.write_all(&[0xFE, 0x00, 0x21, 0x01, 0x20]).await.unwrap();
+        let mut frame = [0; 5];
+        bridge.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, [0xFE, 0x00, 0x21, 0x01, 0x20]);
+    }
+
+    /// With a backlog of 0 Linux queues one connection and ignores the SYNs after it
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn tcp_connect_times_out() {
+        let listener = net2::TcpBuilder::new_v4()
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .listen(0)
+            .unwrap();
+        let port = Port::Tcp(listener.local_addr().unwrap().to_string());
+        let opts = ConnectOptions {
+            connect_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let _queued = port.open(&opts).await.unwrap();
+        let start = Instant::now();
+        let err = port
+            .open(&opts)
+            .await
+            .err()
+            .expect("connected past a full backlog");
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= opts.connect_timeout);
+    }
+
+    #[tokio::test]
+    async fn tcp_gives_up_after_attempts() {
+        // Nothing listens once it is dropped
+        let port = tcp(&TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let opts = ConnectOptions {
+            attempts: Some(3),
+            backoff: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let err = port
+            .connect(&opts)
+            .await
+            .err()
+            .expect("connected to nothing");
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        // Backoff of 20 ms, then 40 ms
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[tokio::test]
+    async fn tcp_retries_until_bridge_is_up() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let bridge = tokio::spawn(async move {
+            delay_for(Duration::from_millis(100)).await;
+            let mut listener = TcpListener::bind(addr).await.unwrap();
+            listener.accept().await.unwrap()
+        });
+        let opts = ConnectOptions {
+            attempts: None,
+            backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(40),
+            ..Default::default()
+        };
+        Port::Tcp(addr.to_string()).connect(&opts).await.unwrap();
+        bridge.await.unwrap();
+    }
+}
@@ -6,6 +6,7 @@ use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use std::collections::HashMap;
 use std::convert::TryInto;
+use tracing::{trace, warn};
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, FromPrimitive, PartialEq)]
@@ -51,11 +52,11 @@ impl VarType {
             VarType::Struct => {
                 let len = reader.read_u16::<LittleEndian>()?;
                 let mut out = Vec::with_capacity(len.try_into().unwrap());
-                println!("will read {}", len);
+                trace!(len, "reading struct");
                 for _i in 0..len {
                     let val = match VarTypeVal::parse(reader) {
                         Err(err) => {
-                            eprintln!("{}", err);
+                            warn!(error = %err, "can't parse struct member");
                             break;
                         }
                         Ok(val) => val,
@@ -128,15 +129,14 @@ impl In {
                 while cur.get_ref().has_remaining() {
                     let index = match cur.read_u16::<LittleEndian>() {
                         Err(err) => {
-                            eprintln!("Unexpected end: can't read next Attribute index");
-                            eprintln!("Inner error: {}", err);
+                            warn!(error = %err, "unexpected end, can't read next attribute index");
                             break;
                         }
                         Ok(index) => index,
                     };
                     let val = match VarTypeVal::parse(&mut cur) {
                         Err(err) => {
-                            eprintln!("{}", err);
+                            warn!(attr_id = index, error = %err, "can't parse attribute value");
                             break;
                         }
                         Ok(val) => val,
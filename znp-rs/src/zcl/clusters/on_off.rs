@@ -1,5 +1,6 @@
 use super::super::frame::ZclFrame;
 use super::error::{Error, Result};
+use tracing::debug;
 
 #[derive(Debug)]
 pub enum In {}
@@ -7,7 +8,7 @@ impl In {
     pub fn from_cmd(cmd: ZclFrame) -> Result<Self> {
         match cmd.cmd_id {
             _ => {
-                println!("{:x?}", cmd);
+                debug!(cmd_id = cmd.cmd_id, "unhandled ZCL command {:x?}", cmd);
                 Err(Error::unknown_cmd(cmd.cmd_id))
             }
         }
@@ -1,14 +1,165 @@
-use super::areq::AreqOut;
-use super::sreq::Sreq;
+use super::areq::{AreqIn, AreqOut};
+use super::bus::{AreqFilter, Bus, Subscription};
+use super::capture::{Capture, Direction};
+use super::sreq::{Sreq, WithCallback, DEFAULT_TIMEOUT};
+use super::transport::{ConnectOptions, Port, Transport, BOOTLOADER_SKIP_BYTE};
 use super::znp_codec;
 use crate::cmd;
-use futures_util::{future, stream, SinkExt, StreamExt};
+use bytes::BytesMut;
+use futures_util::future::{self, BoxFuture, FutureExt};
+use futures_util::{stream, SinkExt, StreamExt};
+use std::future::Future;
 use std::path::Path;
-use std::time::Duration;
-use tokio::sync::{mpsc, oneshot};
-use tokio::time::timeout;
-use tokio_serial::{Serial, SerialPortSettings};
-use znp_codec::{Subsys, ZnpCmd, ZnpCodec};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{delay_for, timeout};
+use tokio_util::codec::Framed;
+use tracing::{debug, debug_span, error, trace, warn, Instrument};
+use znp_codec::{CodecStats, Subsys, Type, ZnpCmd, ZnpCodec};
+
+type ZnpFramed = Framed<Box<dyn Transport>, ZnpCodec>;
+
+/// Brings the coordinator up, see `Options::startup`. Fails with the error's `Debug` output.
+pub type Startup = Arc<dyn Fn(Sender) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+pub fn startup<F, Fut, E>(f: F) -> Startup
+where
+    F: Fn(Sender) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: std::fmt::Debug,
+{
+    Arc::new(move |znp| {
+        f(znp)
+            .map(|res| res.map_err(|err| format!("{:?}", err)))
+            .boxed()
+    })
+}
+
+#[derive(Clone)]
+pub struct Options {
+    pub connect: ConnectOptions,
+    /// Record every frame in both directions
+    pub capture: Option<Capture>,
+    /// Reopen the port with `connect` backoff when it is lost, holding queued requests meanwhile.
+    /// Reopening ignores `connect.attempts` and keeps trying at `connect.max_backoff`.
+    pub reconnect: bool,
+    /// How long SYS_RESET_IND may take after opening the port or SYS_RESET_REQ.
+    /// Later ones count as unexpected. After reopening, startup waits this long for it.
+    pub reset_timeout: Duration,
+    /// Get past the TI serial bootloader on every (re)connect, before `startup`
+    /// and before `SerialConfig::auto` probes any baud rate
+    pub skip_bootloader: Option<SkipBootloader>,
+    /// Reset the coordinator and rerun `startup` when it stops answering
+    pub watchdog: Option<Watchdog>,
+    /// Run on every (re)connect before queued requests go out,
+    /// e.g. `init_coord::init` to form the network and register endpoints
+    pub startup: Option<Startup>,
+    /// Pause before running `startup` again after it failed, queued requests keep waiting
+    pub startup_retry: Duration,
+    /// For `Sreq::IDEMPOTENT` commands, unless overridden per call
+    pub retry: RetryPolicy,
+    /// AREQs a subscriber can fall behind by before it lags
+    pub bus_capacity: usize,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            connect: Default::default(),
+            capture: None,
+            reconnect: false,
+            reset_timeout: Duration::from_secs(5),
+            skip_bootloader: None,
+            watchdog: None,
+            startup: None,
+            startup_retry: Duration::from_secs(10),
+            retry: Default::default(),
+            bus_capacity: 64,
+        }
+    }
+}
+
+/// CC2652/CC1352 sticks sit in the serial bootloader for a while after booting and
+/// swallow frames. A magic byte makes it jump to ZNP right away, which is alive once it
+/// sends SYS_RESET_IND or answers SYS_PING.
+#[derive(Debug, Clone, Copy)]
+pub struct SkipBootloader {
+    /// SRSP timeout of each SYS_PING, sent back to back
+    pub ping_interval: Duration,
+    /// Start up anyway after this long
+    pub timeout: Duration,
+}
+impl Default for SkipBootloader {
+    fn default() -> Self {
+        SkipBootloader {
+            ping_interval: Duration::from_millis(500),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Z-Stack can hang without the port going away. The watchdog pings periodically and
+/// escalates once SREQs keep timing out, see `WatchdogEvent`.
+#[derive(Debug, Clone, Copy)]
+pub struct Watchdog {
+    /// Between SYS_PINGs
+    pub interval: Duration,
+    /// Consecutive SRSP timeouts, pings or not, before resetting
+    pub max_timeouts: u32,
+    /// How long each reset gets to produce SYS_RESET_IND
+    pub reset_timeout: Duration,
+}
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog {
+            interval: Duration::from_secs(10),
+            max_timeouts: 3,
+            reset_timeout: Duration::from_secs(5),
+        }
+    }
+}
+#[derive(Debug, Clone)]
+pub enum WatchdogEvent {
+    /// This many SREQs in a row got no SRSP
+    Unresponsive(u32),
+    /// Sent SYS_RESET_REQ
+    Resetting(cmd::sys::ResetType),
+    /// SYS_RESET_IND came, startup runs again
+    Restarting,
+    /// Neither reset helped, the watchdog keeps pinging and will try again
+    GaveUp,
+}
+
+/// Resending an SREQ that got no SRSP
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Including the first one, 1 to never retry
+    pub attempts: u32,
+    /// Pause before each retry
+    pub delay: Duration,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Per call overrides for `Sender::sreq_with`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SreqOptions {
+    /// Instead of `Sreq::TIMEOUT`
+    pub timeout: Option<Duration>,
+    /// Instead of `Options::retry`, applies to non-idempotent commands too
+    pub retry: Option<RetryPolicy>,
+    /// Instead of `WithCallback::CALLBACK_TIMEOUT`, see `Sender::sreq_callback_with`
+    pub callback_timeout: Option<Duration>,
+}
 
 #[derive(Debug)]
 pub enum SreqError {
@@ -16,130 +167,1047 @@ pub enum SreqError {
     SerialPortGone,
     TimedOut,
     IO(std::io::Error),
+    /// The SRSP status was not success, so no callback follows
+    Status(u8),
+    /// The SRSP came but the callback didn't
+    NoCallback,
+    /// RPC_Error instead of the SRSP: 1 unsupported subsystem, 2 unsupported command,
+    /// 3 bad parameter
+    Rpc(u8),
 }
 #[derive(Debug)]
 pub enum AreqError {
+    SerialPortGone,
     IO(std::io::Error),
 }
+/// Problems the receiver ran into, see `Sender::status`
+#[derive(Debug, Clone)]
+pub enum ConnectionError {
+    /// Reading from the port failed
+    IO(Arc<std::io::Error>),
+    /// The port reached end of file, e.g. the TCP bridge hung up
+    Closed,
+    /// An SRSP no SREQ was waiting for, probably a late one
+    UnexpectedSrsp(ZnpCmd),
+    /// A POLL or SREQ, which only the host should send
+    UnexpectedFrame(ZnpCmd),
+}
+#[derive(Debug, Clone)]
+pub enum Status {
+    /// Something unexpected the connection survived
+    Error(ConnectionError),
+    /// The port was lost, the in-flight SREQ fails with `SerialPortGone`.
+    /// Queued requests wait for `Ready` when reconnecting, otherwise they fail too.
+    Disconnected(ConnectionError),
+    /// SYS_RESET_IND, the coordinator (re)booted
+    Reset(cmd::sys::Reset),
+    /// Follows `Reset` when nobody asked for one, e.g. after a firmware crash.
+    /// Startup runs again to restore endpoints and the network.
+    UnexpectedReset(cmd::sys::ResetReason),
+    /// Startup finished, queued requests go out
+    Ready,
+    /// Startup failed or panicked, queued requests wait for it to be retried
+    StartupFailed(String),
+    Watchdog(WatchdogEvent),
+    /// The port was lost without `Options::reconnect`. Every request fails from now on.
+    Closed,
+}
 #[derive(Debug)]
 struct Callback {
     cb: oneshot::Sender<ZnpCmd>,
     subsys: Subsys,
     cmd_id: u8,
 }
+/// Someone waiting for a particular AREQ, see `Sender::sreq_callback`
+struct Waiter {
+    subsys: Subsys,
+    cmd_id: u8,
+    matches: Box<dyn Fn(&ZnpCmd) -> bool + Send>,
+    tx: oneshot::Sender<ZnpCmd>,
+}
+type Waiters = Arc<std::sync::Mutex<Vec<Waiter>>>;
+/// The receiving end of a `Waiter`, dropping it unregisters the waiter
+struct WaiterGuard {
+    waiters: Waiters,
+    rx: oneshot::Receiver<ZnpCmd>,
+}
+impl Drop for WaiterGuard {
+    fn drop(&mut self) {
+        self.rx.close();
+        if let Ok(mut waiters) = self.waiters.lock() {
+            waiters.retain(|waiter| !waiter.tx.is_closed());
+        }
+    }
+}
+/// Hands `frame` to everyone waiting for it
+fn wake(waiters: &Waiters, frame: &ZnpCmd) {
+    let mut waiters = match waiters.lock() {
+        Ok(waiters) => waiters,
+        Err(_) => return,
+    };
+    let (woken, rest) = waiters
+        .drain(..)
+        .filter(|waiter| !waiter.tx.is_closed())
+        .partition::<Vec<_>, _>(|waiter| {
+            waiter.subsys == frame.subsys()
+                && waiter.cmd_id == frame.cmd_id()
+                && (waiter.matches)(frame)
+        });
+    *waiters = rest;
+    for waiter in woken {
+        let _ = waiter.tx.send(frame.clone());
+    }
+}
+/// Tells resets we asked for from crashes
+struct Resets {
+    /// A SYS_RESET_IND until then was asked for
+    expected_until: std::sync::Mutex<Option<Instant>>,
+    unexpected_tx: mpsc::UnboundedSender<cmd::sys::ResetReason>,
+}
+impl Resets {
+    fn expect_within(&self, window: Duration) {
+        if let Ok(mut until) = self.expected_until.lock() {
+            *until = Some(Instant::now() + window);
+        }
+    }
+    /// Whether a reset now was asked for, each request covers one reset
+    fn take_expected(&self) -> bool {
+        match self.expected_until.lock() {
+            Ok(mut until) => until.take().map_or(false, |until| Instant::now() <= until),
+            Err(_) => true,
+        }
+    }
+}
 enum SendJob {
-    Sreq(ZnpCmd, Callback),
-    Areq(ZnpCmd),
+    /// With the SRSP timeout
+    Sreq(ZnpCmd, Duration, oneshot::Sender<Result<ZnpCmd, SreqError>>),
+    Areq(ZnpCmd, oneshot::Sender<Result<(), AreqError>>),
 }
 async fn receiver(
-    cbs_rx: mpsc::Receiver<Callback>,
-    mut sp_rx: futures_util::stream::SplitStream<tokio_util::codec::Framed<Serial, ZnpCodec>>,
-    mut areq_tx: mpsc::Sender<crate::cmd::Areq>,
-) {
-    let mut cbs_rx = cbs_rx.filter(|cb| future::ready(!cb.cb.is_closed()));
-    while let Some(frame) = sp_rx.next().await {
+    cbs_rx: mpsc::UnboundedReceiver<Callback>,
+    mut sp_rx: stream::SplitStream<ZnpFramed>,
+    bus: Bus,
+    status_tx: broadcast::Sender<Status>,
+    waiters: Waiters,
+    capture: Option<Capture>,
+    resets: Arc<Resets>,
+) -> ConnectionError {
+    // Having no status subscribers is fine
+    let report = |status| {
+        let _ = status_tx.send(status);
+    };
+    let mut cbs_rx = cbs_rx;
+    // Callback of the SREQ in flight
+    let mut pending: Option<Callback> = None;
+    loop {
         use znp_codec::Type::{AREQ, SRSP};
-        match frame {
-            Err(err) => {
-                eprintln!("{}", err);
-                break;
+        let frame = match sp_rx.next().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(err)) => return ConnectionError::IO(Arc::new(err)),
+            None => return ConnectionError::Closed,
+        };
+        trace!(?frame, "received");
+        if let Some(capture) = &capture {
+            capture.record(Direction::In, &frame);
+        }
+        match frame.typ() {
+            SRSP => {
+                // The writer queues the callback before writing the SREQ, so there is
+                // no waiting here. Only the last one can still be live, since the writer
+                // has a single SREQ in flight.
+                while let Ok(cb) = cbs_rx.try_recv() {
+                    pending = Some(cb);
+                }
+                let answers = |subsys: Subsys, cmd_id: u8| match frame.rpc_error() {
+                    Some((_, cmd0, cmd1)) => cmd0 & 0x1F == subsys as u8 && cmd1 == cmd_id,
+                    None => subsys == frame.subsys() && cmd_id == frame.cmd_id(),
+                };
+                match pending.take() {
+                    Some(Callback { cb, subsys, cmd_id }) if answers(subsys, cmd_id) => {
+                        if let Err(frame) = cb.send(frame) {
+                            warn!(
+                                subsys = ?frame.subsys(),
+                                cmd_id = frame.cmd_id(),
+                                "late SRSP, dropping"
+                            );
+                        }
+                    }
+                    cb => {
+                        // Probably a late SRSP to an SREQ that timed out
+                        pending = cb.filter(|cb| !cb.cb.is_closed());
+                        report(Status::Error(ConnectionError::UnexpectedSrsp(frame)));
+                    }
+                }
             }
-            Ok(frame) => match frame.typ() {
-                SRSP => {
-                    let cb = loop {
-                        match timeout(Duration::from_millis(100), cbs_rx.next()).await {
-                            Err(err) => {
-                                panic!(err);
-                            }
-                            // Ok(Async::NotReady) => {
-                            //     eprintln!("Unexpected SRSP: {:?}", frame);
-                            //     panic!("SRSP no one was waiting for");
-                            // }
-                            Ok(None) => {
-                                panic!("callback sender is finished and closed");
-                            }
-                            Ok(Some(Callback { cb, subsys, cmd_id })) => {
-                                if subsys != frame.subsys() || cmd_id != frame.cmd_id() {
-                                    eprintln!("Mismatched SRSP, probably old: {:?}", frame);
-                                    continue;
-                                } else {
-                                    break cb;
-                                }
+            AREQ => {
+                use crate::cmd::Areq;
+                wake(&waiters, &frame);
+                match Areq::from_subsys(frame) {
+                    Ok(areq) => {
+                        if let Areq::Sys(cmd::sys::In::Reset(reset)) = &areq {
+                            report(Status::Reset(reset.clone()));
+                            if !resets.take_expected() {
+                                warn!(reason = ?reset.reason, "unexpected SYS_RESET_IND");
+                                report(Status::UnexpectedReset(reset.reason));
+                                let _ = resets.unexpected_tx.send(reset.reason);
                             }
                         }
-                    };
-                    let cb_res = cb.send(frame);
-                    if let Err(frame) = cb_res {
-                        eprintln!("Late SRSP, dropping: {:?}", frame);
-                        // TODO: Determine if this is the next SRSP, or a late one.
+                        bus.publish(areq);
                     }
+                    Err(err) => warn!(error = ?err, "malformed AREQ"),
                 }
-                AREQ => {
-                    use crate::cmd::Areq;
-                    match Areq::from_subsys(frame) {
-                        Ok(areq) => {
-                            (&mut areq_tx).send(areq).await.expect("Couldn't send AREQ");
-                        }
-                        Err(cmd::error::Error::Unimplemented { subsys, cmd_id }) => {
-                            println!("Unimplemented AREQ: {:?} Cmd1 = {:#X?}", subsys, cmd_id)
-                        }
-                        Err(err) => println!("Unimplemented AREQ: {:#X?}", err),
+            }
+            _ => report(Status::Error(ConnectionError::UnexpectedFrame(frame))),
+        }
+    }
+}
+/// Owns the write half of the port. An SREQ is only written once the previous one
+/// got its SRSP or timed out, since ZNP allows a single outstanding SREQ.
+struct Writer {
+    sp_tx: stream::SplitSink<ZnpFramed, ZnpCmd>,
+    cbs_tx: mpsc::UnboundedSender<Callback>,
+    capture: Option<Capture>,
+    /// Consecutive SRSP timeouts, for the watchdog
+    timeouts: Arc<AtomicU32>,
+    resets: Arc<Resets>,
+    reset_timeout: Duration,
+}
+impl Writer {
+    async fn send(&mut self, job: SendJob) {
+        let frame = match &job {
+            SendJob::Sreq(frame, _, _) | SendJob::Areq(frame, _) => frame,
+        };
+        trace!(?frame, "sending");
+        if let Some(capture) = &self.capture {
+            capture.record(Direction::Out, frame);
+        }
+        match job {
+            SendJob::Sreq(frame, srsp_timeout, res_tx) => {
+                let (cb_tx, cb_rx) = oneshot::channel();
+                let cb = Callback {
+                    cb: cb_tx,
+                    subsys: frame.subsys(),
+                    cmd_id: frame.cmd_id(),
+                };
+                if self.cbs_tx.send(cb).is_err() {
+                    let _ = res_tx.send(Err(SreqError::SerialPortGone));
+                    return;
+                }
+                if let Err(err) = self.sp_tx.send(frame).await {
+                    let _ = res_tx.send(Err(SreqError::IO(err)));
+                    return;
+                }
+                let res = match timeout(srsp_timeout, cb_rx).await {
+                    Err(_) => {
+                        self.timeouts.fetch_add(1, Ordering::Relaxed);
+                        Err(SreqError::TimedOut)
+                    }
+                    Ok(Err(_)) => Err(SreqError::SerialPortGone),
+                    Ok(Ok(srsp)) => {
+                        self.timeouts.store(0, Ordering::Relaxed);
+                        Ok(srsp)
                     }
+                };
+                // The caller may have given up already
+                let _ = res_tx.send(res);
+            }
+            SendJob::Areq(frame, res_tx) => {
+                if frame.subsys() == Subsys::SYS
+                    && frame.cmd_id() == <cmd::sys::ResetReq as AreqOut>::CMD_ID
+                {
+                    self.resets.expect_within(self.reset_timeout);
                 }
-                _ => panic!("incoming POLL or SREQ"),
-            },
+                let res = self.sp_tx.send(frame).await.map_err(AreqError::IO);
+                let _ = res_tx.send(res);
+            }
         }
     }
 }
+
+/// Everything that outlives a single connection
+struct Shared {
+    status_tx: broadcast::Sender<Status>,
+    bus: Bus,
+    waiters: Waiters,
+    stats: Arc<CodecStats>,
+    opts: Options,
+}
+impl Shared {
+    fn report(&self, status: Status) {
+        let _ = self.status_tx.send(status);
+    }
+}
+
+enum Event {
+    Job(SendJob),
+    StartupDone(Result<(), String>),
+    /// The watchdog reset the coordinator, or it reset by itself
+    Restart,
+    Lost(ConnectionError),
+    /// Every `Sender` was dropped
+    Finished,
+}
+
+/// Dropping it aborts the task, which dropping a tokio `JoinHandle` doesn't
+struct StartupTask {
+    task: tokio::task::JoinHandle<Result<Result<(), String>, future::Aborted>>,
+    abort: future::AbortHandle,
+}
+impl Drop for StartupTask {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+/// Runs one connection until it is lost. Startup requests jump the queue,
+/// everyone else's requests wait in `jobs_rx` until startup is done.
+async fn session(
+    io: Box<dyn Transport>,
+    jobs_rx: &mut mpsc::Receiver<SendJob>,
+    shared: &Shared,
+    reconnected: bool,
+) -> Option<ConnectionError> {
+    let mut sp = Framed::new(io, ZnpCodec::with_stats(shared.stats.clone()));
+    if shared.opts.skip_bootloader.is_some() {
+        // Not a frame, so it bypasses the codec
+        if let Err(err) = sp.get_mut().write_all(&[BOOTLOADER_SKIP_BYTE]).await {
+            return Some(ConnectionError::IO(Arc::new(err)));
+        }
+    }
+    let (sp_tx, sp_rx) = sp.split();
+    let (cbs_tx, cbs_rx) = mpsc::unbounded_channel::<Callback>();
+    let mut status_rx = shared.status_tx.subscribe();
+    let (unexpected_tx, mut unexpected_rx) = mpsc::unbounded_channel();
+    let resets = Arc::new(Resets {
+        expected_until: Default::default(),
+        unexpected_tx,
+    });
+    // Opening the port may reboot the dongle, and skipping the bootloader boots ZNP
+    let boot_window = match shared.opts.skip_bootloader {
+        Some(skip) => skip.timeout.max(shared.opts.reset_timeout),
+        None => shared.opts.reset_timeout,
+    };
+    resets.expect_within(boot_window);
+    let mut receiver = tokio::spawn(receiver(
+        cbs_rx,
+        sp_rx,
+        shared.bus.clone(),
+        shared.status_tx.clone(),
+        shared.waiters.clone(),
+        shared.opts.capture.clone(),
+        resets.clone(),
+    ));
+    let timeouts = Arc::new(AtomicU32::new(0));
+    let mut writer = Writer {
+        sp_tx,
+        cbs_tx,
+        capture: shared.opts.capture.clone(),
+        timeouts: timeouts.clone(),
+        resets,
+        reset_timeout: shared.opts.reset_timeout,
+    };
+    if let Some(skip) = shared.opts.skip_bootloader {
+        // Also covers waiting for SYS_RESET_IND after reconnecting
+        let alive = wait_alive(&mut writer, &mut status_rx, skip.ping_interval);
+        if timeout(skip.timeout, alive).await.is_err() {
+            warn!("ZNP silent after skipping the bootloader, starting up anyway");
+        }
+    } else if reconnected {
+        // A re-enumerated dongle boots and says so, a TCP bridge might not
+        let reset = async {
+            while let Ok(status) = status_rx.recv().await {
+                if let Status::Reset(_) = status {
+                    break;
+                }
+            }
+        };
+        if timeout(shared.opts.reset_timeout, reset).await.is_err() {
+            warn!("no SYS_RESET_IND after reconnecting, starting up anyway");
+        }
+    }
+    let (prio_tx, mut prio_rx) = mpsc::channel::<SendJob>(8);
+    // Jumps the queue, for startup and the watchdog
+    let prio_sender = || Sender {
+        jobs_tx: prio_tx.clone(),
+        bus: shared.bus.clone(),
+        status_tx: shared.status_tx.clone(),
+        waiters: shared.waiters.clone(),
+        stats: shared.stats.clone(),
+        retry: shared.opts.retry,
+    };
+    let spawn_startup = |delay: Option<Duration>| {
+        let startup = shared.opts.startup.as_ref()?.clone();
+        let znp = prio_sender();
+        let (run, abort) = future::abortable(async move {
+            if let Some(delay) = delay {
+                delay_for(delay).await;
+            }
+            startup(znp).await
+        });
+        Some(StartupTask {
+            task: tokio::spawn(run),
+            abort,
+        })
+    };
+    let mut startup = spawn_startup(None);
+    if startup.is_none() {
+        shared.report(Status::Ready);
+    }
+    let (restart_tx, mut restart_rx) = mpsc::channel::<()>(1);
+    if let Some(config) = shared.opts.watchdog {
+        tokio::spawn(watchdog(prio_sender(), config, timeouts, restart_tx));
+    }
+    loop {
+        let event = future::poll_fn(|cx| {
+            if let Poll::Ready(reason) = receiver.poll_unpin(cx) {
+                // The receiver panicking counts as losing the port
+                return Poll::Ready(Event::Lost(reason.unwrap_or(ConnectionError::Closed)));
+            }
+            if let Poll::Ready(Some(())) = restart_rx.poll_next_unpin(cx) {
+                return Poll::Ready(Event::Restart);
+            }
+            if let Poll::Ready(Some(_)) = unexpected_rx.poll_next_unpin(cx) {
+                return Poll::Ready(Event::Restart);
+            }
+            if let Poll::Ready(Some(job)) = prio_rx.poll_next_unpin(cx) {
+                return Poll::Ready(Event::Job(job));
+            }
+            match &mut startup {
+                Some(startup) => {
+                    if let Poll::Ready(res) = startup.task.poll_unpin(cx) {
+                        let res = match res {
+                            Ok(Ok(res)) => res,
+                            Ok(Err(future::Aborted)) => Err("startup aborted".to_owned()),
+                            Err(_) => Err("startup panicked".to_owned()),
+                        };
+                        return Poll::Ready(Event::StartupDone(res));
+                    }
+                }
+                None => match jobs_rx.poll_next_unpin(cx) {
+                    Poll::Ready(Some(job)) => return Poll::Ready(Event::Job(job)),
+                    Poll::Ready(None) => return Poll::Ready(Event::Finished),
+                    Poll::Pending => {}
+                },
+            }
+            Poll::Pending
+        })
+        .await;
+        match event {
+            Event::Job(job) => writer.send(job).await,
+            Event::StartupDone(Ok(())) => {
+                startup = None;
+                shared.report(Status::Ready);
+            }
+            Event::StartupDone(Err(err)) => {
+                error!(error = %err, "startup failed, retrying");
+                shared.report(Status::StartupFailed(err));
+                startup = spawn_startup(Some(shared.opts.startup_retry));
+            }
+            Event::Restart => {
+                // Queued requests wait for `Ready` again. Replacing a running startup
+                // aborts it, so two never interleave their requests.
+                startup = spawn_startup(None);
+                if startup.is_none() {
+                    shared.report(Status::Ready);
+                }
+            }
+            Event::Lost(reason) => return Some(reason),
+            Event::Finished => return None,
+        }
+    }
+}
+
+/// Pings until the coordinator answers or reports a reset
+async fn wait_alive(
+    writer: &mut Writer,
+    status_rx: &mut broadcast::Receiver<Status>,
+    ping_interval: Duration,
+) {
+    loop {
+        let (res_tx, res_rx) = oneshot::channel();
+        let ping = SendJob::Sreq(cmd::sys::Ping.frame(), ping_interval, res_tx);
+        writer.send(ping).await;
+        if let Ok(Ok(_)) = res_rx.await {
+            return;
+        }
+        loop {
+            match status_rx.try_recv() {
+                Ok(Status::Reset(_)) => return,
+                Ok(_) | Err(broadcast::TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+        debug!("no answer to SYS_PING yet");
+    }
+}
+
+/// Pings every `config.interval`, resets once `config.max_timeouts` SREQs in a row
+/// went unanswered. Ends with the session.
+async fn watchdog(
+    znp: Sender,
+    config: Watchdog,
+    timeouts: Arc<AtomicU32>,
+    mut restart_tx: mpsc::Sender<()>,
+) {
+    use cmd::sys::{Ping, ResetReq, ResetType};
+    let report = |event| {
+        let _ = znp.status_tx.send(Status::Watchdog(event));
+    };
+    let ping = SreqOptions {
+        retry: Some(RetryPolicy {
+            attempts: 1,
+            ..znp.retry
+        }),
+        ..Default::default()
+    };
+    loop {
+        delay_for(config.interval).await;
+        if let Err(SreqError::SerialPortGone) = znp.sreq_with(Ping, ping).await {
+            return;
+        }
+        let count = timeouts.load(Ordering::Relaxed);
+        if count < config.max_timeouts {
+            continue;
+        }
+        warn!(timeouts = count, "coordinator unresponsive");
+        report(WatchdogEvent::Unresponsive(count));
+        let mut recovered = false;
+        for &typ in &[ResetType::Soft, ResetType::Hard] {
+            let mut status = znp.status();
+            report(WatchdogEvent::Resetting(typ));
+            if znp.areq(ResetReq { typ }).await.is_err() {
+                return;
+            }
+            let reset = async {
+                while let Ok(status) = status.recv().await {
+                    if let Status::Reset(_) = status {
+                        return true;
+                    }
+                }
+                false
+            };
+            if let Ok(true) = timeout(config.reset_timeout, reset).await {
+                recovered = true;
+                break;
+            }
+        }
+        if !recovered {
+            error!("coordinator didn't come back after a hard reset");
+            report(WatchdogEvent::GaveUp);
+            continue;
+        }
+        timeouts.store(0, Ordering::Relaxed);
+        report(WatchdogEvent::Restarting);
+        if restart_tx.send(()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs sessions back to back, reopening `port` in between if given
+async fn supervisor(
+    mut io: Box<dyn Transport>,
+    port: Option<Port>,
+    mut jobs_rx: mpsc::Receiver<SendJob>,
+    shared: Shared,
+) {
+    // An unattended hub has to wait out the dongle however long it is gone
+    let reconnect = ConnectOptions {
+        attempts: None,
+        ..shared.opts.connect.clone()
+    };
+    let mut reconnected = false;
+    loop {
+        let reason = match session(io, &mut jobs_rx, &shared, reconnected).await {
+            Some(reason) => reason,
+            None => {
+                shared.bus.close();
+                return;
+            }
+        };
+        shared.report(Status::Disconnected(reason));
+        let port = match &port {
+            Some(port) => port,
+            None => break,
+        };
+        io = match port.connect(&reconnect).await {
+            Ok(io) => io,
+            Err(err) => {
+                error!(?port, error = %err, "giving up reconnecting");
+                break;
+            }
+        };
+        reconnected = true;
+    }
+    shared.report(Status::Closed);
+    shared.bus.close();
+}
+
+/// Cheap to clone handle to one coordinator. Requests from all clones share a
+/// FIFO queue, tasks waiting for room in it are served in arrival order.
+#[derive(Clone)]
 pub struct Sender {
-    sp_tx: stream::SplitSink<tokio_util::codec::Framed<Serial, ZnpCodec>, ZnpCmd>,
-    cbs_tx: mpsc::Sender<Callback>,
+    jobs_tx: mpsc::Sender<SendJob>,
+    bus: Bus,
+    status_tx: broadcast::Sender<Status>,
+    waiters: Waiters,
+    stats: Arc<CodecStats>,
+    retry: RetryPolicy,
 }
 impl Sender {
-    pub fn from_path<P>(path: P) -> (Self, mpsc::Receiver<crate::cmd::Areq>)
+    pub fn from_path<P>(path: P) -> std::io::Result<Self>
     where
         P: AsRef<Path>,
     {
-        let sp_settings = SerialPortSettings {
-            baud_rate: 115_000,
-            ..Default::default() // 8-N-1 is default
-        };
-        let sp = Serial::from_path(path, &sp_settings).unwrap();
-        let sp = tokio_util::codec::Framed::new(sp, ZnpCodec);
-        let (cbs_tx, cbs_rx) = mpsc::channel::<Callback>(2);
-        let (areq_tx, areq_rx) = mpsc::channel::<crate::cmd::Areq>(1);
-        let (sp_tx, sp_rx) = sp.split();
-        tokio::spawn(receiver(cbs_rx, sp_rx, areq_tx));
-        (Sender { sp_tx, cbs_tx }, areq_rx)
-    }
-    pub async fn sreq<S>(&mut self, req: S) -> Result<S::Srsp, SreqError>
+        let sp = super::transport::open_serial(path, &Default::default())?;
+        Ok(Self::new(sp))
+    }
+    /// Open a serial device or a `tcp://host:port` bridge, retrying per `opts.connect`.
+    /// With `opts.reconnect` the port is reopened whenever it is lost.
+    pub async fn connect(port: &Port, opts: &Options) -> std::io::Result<Self> {
+        let mut opts = opts.clone();
+        // Baud rate detection probes before the session could skip the bootloader
+        opts.connect.serial.skip_bootloader |= opts.skip_bootloader.is_some();
+        let io = port.connect(&opts.connect).await?;
+        let port = if opts.reconnect {
+            Some(port.clone())
+        } else {
+            None
+        };
+        Ok(Self::spawn(io, port, opts))
+    }
+    /// Speak ZNP over an already open byte stream
+    pub fn new<T>(io: T) -> Self
+    where
+        T: Transport + 'static,
+    {
+        Self::with_options(io, Default::default())
+    }
+    /// Over an already open byte stream there is nothing to reopen, `opts.reconnect` is ignored
+    pub fn with_options<T>(io: T, opts: Options) -> Self
+    where
+        T: Transport + 'static,
+    {
+        Self::spawn(Box::new(io), None, opts)
+    }
+    fn spawn(io: Box<dyn Transport>, port: Option<Port>, opts: Options) -> Self {
+        let (jobs_tx, jobs_rx) = mpsc::channel::<SendJob>(32);
+        let (status_tx, _) = broadcast::channel::<Status>(16);
+        let stats = Arc::new(CodecStats::default());
+        let retry = opts.retry;
+        let waiters = Waiters::default();
+        let shared = Shared {
+            status_tx: status_tx.clone(),
+            bus: Bus::new(opts.bus_capacity),
+            waiters: waiters.clone(),
+            stats: stats.clone(),
+            opts,
+        };
+        let bus = shared.bus.clone();
+        tokio::spawn(supervisor(io, port, jobs_rx, shared));
+        Sender {
+            jobs_tx,
+            bus,
+            status_tx,
+            waiters,
+            stats,
+            retry,
+        }
+    }
+    /// Incoming AREQs matching `filter`, from the time of subscribing on
+    pub fn subscribe(&self, filter: AreqFilter) -> Subscription {
+        self.bus.subscribe(filter)
+    }
+    /// Connection events, from the time of subscribing on
+    pub fn status(&self) -> broadcast::Receiver<Status> {
+        self.status_tx.subscribe()
+    }
+    /// Bytes and frames dropped by the decoder so far
+    pub fn codec_stats(&self) -> &CodecStats {
+        &self.stats
+    }
+    pub async fn sreq<S>(&self, req: S) -> Result<S::Srsp, SreqError>
     where
         S: Sreq + 'static,
     {
-        let (cb_tx, cb_rx) = oneshot::channel();
-        let frame = req.frame();
-        let cb = Callback {
-            cb: cb_tx,
-            subsys: frame.subsys(),
-            cmd_id: frame.cmd_id(),
-        };
-        self.cbs_tx.send(cb).await.expect("receiver gone");
-        self.sp_tx.send(frame).await.expect("SREQ send IO error");
-        let cb_rx = timeout(Duration::from_millis(1000), cb_rx);
-        let srsp = cb_rx
-            .await
-            .map_err(|err| SreqError::TimedOut)?
-            .map_err(|err| SreqError::SerialPortGone)?;
+        self.sreq_with(req, SreqOptions::default()).await
+    }
+    /// Timed out attempts are retried for `Sreq::IDEMPOTENT` commands,
+    /// or whenever `opts.retry` is given
+    pub async fn sreq_with<S>(&self, req: S, opts: SreqOptions) -> Result<S::Srsp, SreqError>
+    where
+        S: Sreq + 'static,
+    {
+        let srsp = self
+            .sreq_frame(req.frame(), S::TIMEOUT, S::IDEMPOTENT, opts)
+            .await?;
         let srsp = S::parse_res(srsp).map_err(SreqError::BadResponse)?;
         Ok(srsp)
     }
-    pub async fn areq<A>(&mut self, req: A)
+    async fn sreq_frame(
+        &self,
+        frame: ZnpCmd,
+        srsp_timeout: Duration,
+        idempotent: bool,
+        opts: SreqOptions,
+    ) -> Result<ZnpCmd, SreqError> {
+        let srsp_timeout = opts.timeout.unwrap_or(srsp_timeout);
+        let retry = match opts.retry {
+            Some(retry) => retry,
+            None if idempotent => self.retry,
+            None => RetryPolicy {
+                attempts: 1,
+                ..self.retry
+            },
+        };
+        let span = debug_span!("sreq", subsys = ?frame.subsys(), cmd_id = frame.cmd_id());
+        let round_trip = async move {
+            let mut attempt = 1;
+            loop {
+                let (res_tx, res_rx) = oneshot::channel();
+                self.jobs_tx
+                    .clone()
+                    .send(SendJob::Sreq(frame.clone(), srsp_timeout, res_tx))
+                    .await
+                    .map_err(|_| SreqError::SerialPortGone)?;
+                match res_rx.await.map_err(|_| SreqError::SerialPortGone)? {
+                    Err(SreqError::TimedOut) if attempt < retry.attempts => {
+                        warn!(attempt, "no SRSP, retrying");
+                        delay_for(retry.delay).await;
+                        attempt += 1;
+                    }
+                    Ok(srsp) => match srsp.rpc_error() {
+                        Some((status, _, _)) => return Err(SreqError::Rpc(status)),
+                        None => return Ok(srsp),
+                    },
+                    res => return res,
+                }
+            }
+        };
+        let res = round_trip.instrument(span.clone()).await;
+        if let Err(err) = &res {
+            span.in_scope(|| debug!(error = ?err, "SREQ failed"));
+        }
+        res
+    }
+    /// Send a request like `zdo::SimpleDescReq` and wait for the AREQ answering it
+    pub async fn sreq_callback<S>(&self, req: S) -> Result<S::Callback, SreqError>
+    where
+        S: WithCallback + Send + 'static,
+    {
+        self.sreq_callback_with(req, SreqOptions::default()).await
+    }
+    /// `opts.callback_timeout` bounds the wait for the AREQ, the rest applies to the SRSP
+    pub async fn sreq_callback_with<S>(
+        &self,
+        req: S,
+        opts: SreqOptions,
+    ) -> Result<S::Callback, SreqError>
+    where
+        S: WithCallback + Send + 'static,
+    {
+        let frame = req.frame();
+        let (tx, rx) = oneshot::channel();
+        let matches = move |cmd: &ZnpCmd| match <S::Callback as AreqIn>::parse(cmd.clone()) {
+            Ok(callback) => req.matches(&callback),
+            Err(_) => false,
+        };
+        // Registered before sending, the callback can beat the SRSP
+        if let Ok(mut waiters) = self.waiters.lock() {
+            waiters.push(Waiter {
+                subsys: <S::Callback as AreqIn>::SUBSYS,
+                cmd_id: <S::Callback as AreqIn>::CMD_ID,
+                matches: Box::new(matches),
+                tx,
+            });
+        }
+        let mut guard = WaiterGuard {
+            waiters: self.waiters.clone(),
+            rx,
+        };
+        let srsp = self
+            .sreq_frame(frame, S::TIMEOUT, S::IDEMPOTENT, opts)
+            .await?;
+        let srsp = S::parse_res(srsp).map_err(SreqError::BadResponse)?;
+        match S::status(&srsp) {
+            0 => {}
+            status => return Err(SreqError::Status(status)),
+        }
+        let callback_timeout = opts.callback_timeout.unwrap_or(S::CALLBACK_TIMEOUT);
+        let callback = timeout(callback_timeout, &mut guard.rx)
+            .await
+            .map_err(|_| SreqError::NoCallback)?
+            .map_err(|_| SreqError::SerialPortGone)?;
+        <S::Callback as AreqIn>::parse(callback).map_err(SreqError::BadResponse)
+    }
+    /// SREQ without a typed `Sreq`, e.g. a firmware-specific command. Returns the SRSP as is.
+    pub async fn sreq_raw(
+        &self,
+        subsys: Subsys,
+        cmd_id: u8,
+        body: &[u8],
+        opts: SreqOptions,
+    ) -> Result<ZnpCmd, SreqError> {
+        let frame = ZnpCmd::new(Type::SREQ, subsys, cmd_id, BytesMut::from(body));
+        self.sreq_frame(frame, DEFAULT_TIMEOUT, false, opts).await
+    }
+    pub async fn areq<A>(&self, req: A) -> Result<(), AreqError>
     where
         A: AreqOut + 'static,
     {
-        self.sp_tx
-            .send(req.frame())
+        self.areq_frame(req.frame()).await
+    }
+    /// AREQ without a typed `AreqOut`
+    pub async fn areq_raw(&self, subsys: Subsys, cmd_id: u8, body: &[u8]) -> Result<(), AreqError> {
+        let frame = ZnpCmd::new(Type::AREQ, subsys, cmd_id, BytesMut::from(body));
+        self.areq_frame(frame).await
+    }
+    async fn areq_frame(&self, frame: ZnpCmd) -> Result<(), AreqError> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.jobs_tx
+            .clone()
+            .send(SendJob::Areq(frame, res_tx))
             .await
-            .expect("AREQ send IO error");
+            .map_err(|_| AreqError::SerialPortGone)?;
+        res_rx.await.map_err(|_| AreqError::SerialPortGone)?
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cmd::sys;
+    use crate::cmd::types::IEEEAddr;
+    use crate::sim::{SimHandle, Simulator};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, UnixStream};
+
+    async fn wait_for<F: Fn(&Status) -> bool>(status: &mut broadcast::Receiver<Status>, f: F) {
+        let wait = async {
+            while let Ok(status) = status.recv().await {
+                if f(&status) {
+                    return;
+                }
+            }
+            panic!("status channel closed");
+        };
+        timeout(Duration::from_secs(5), wait).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscriptions_end_when_closed() {
+        let (io, handle) = Simulator::new().spawn().unwrap();
+        let znp = Sender::new(io);
+        let mut sub = znp.subscribe(Default::default());
+        handle.hang_up();
+        let recv = timeout(Duration::from_secs(5), sub.recv()).await;
+        assert!(recv.unwrap().is_none());
+        assert!(znp.subscribe(Default::default()).recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_port_loss() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = Port::Tcp(listener.local_addr().unwrap().to_string());
+        // A fake bridge with a different stick behind each connection
+        let (sims_tx, mut sims_rx) = mpsc::unbounded_channel::<SimHandle>();
+        tokio::spawn(async move {
+            for ieee_addr in 1.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut sim = Simulator::new();
+                sim.device_mut().ieee_addr = IEEEAddr(ieee_addr);
+                if sims_tx.send(sim.serve(stream)).is_err() {
+                    return;
+                }
+            }
+        });
+        let opts = Options {
+            reconnect: true,
+            connect: ConnectOptions {
+                backoff: Duration::from_millis(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let znp = Sender::connect(&port, &opts).await.unwrap();
+        let mut status = znp.status();
+        assert_eq!(znp.sreq(sys::GetExtAddr).await.unwrap().ext_addr.0, 1);
+
+        sims_rx.recv().await.unwrap().hang_up();
+        wait_for(&mut status, |status| {
+            matches!(status, Status::Disconnected(_))
+        })
+        .await;
+        let second = sims_rx.recv().await.unwrap();
+        // Like a re-enumerated dongle booting
+        second
+            .inject(&sys::Reset {
+                reason: sys::ResetReason::PowerUp,
+                transport_rev: 2,
+                product_id: 1,
+                major_rel: 2,
+                minor_rel: 7,
+                hw_rev: 1,
+            })
+            .unwrap();
+        wait_for(&mut status, |status| matches!(status, Status::Ready)).await;
+        assert_eq!(znp.sreq(sys::GetExtAddr).await.unwrap().ext_addr.0, 2);
+    }
+
+    #[tokio::test]
+    async fn skips_bootloader_before_first_ping() {
+        let (host, mut stick) = UnixStream::pair().unwrap();
+        let opts = Options {
+            skip_bootloader: Some(SkipBootloader {
+                ping_interval: Duration::from_millis(100),
+                timeout: Duration::from_secs(5),
+            }),
+            ..Default::default()
+        };
+        let znp = Sender::with_options(host, opts);
+        let mut status = znp.status();
+        let mut first = [0; 6];
+        stick.read_exact(&mut first).await.unwrap();
+        // Then SYS_PING
+        assert_eq!(first, [BOOTLOADER_SKIP_BYTE, 0xFE, 0x00, 0x21, 0x01, 0x20]);
+        // ZNP is up now, the next ping gets an answer
+        let _sim = Simulator::new().serve(stick);
+        wait_for(&mut status, |status| matches!(status, Status::Ready)).await;
+    }
+
+    #[tokio::test]
+    async fn watchdog_escalates_to_hard_reset() {
+        use sys::ResetType;
+        let mut sim = Simulator::new();
+        // Hung: no SRSPs, and only a hard reset brings it back
+        sim.on_raw(Subsys::SYS, <sys::Ping as Sreq>::CMD_ID, |_, _| None)
+            .on_areq(|dev, req: sys::ResetReq| {
+                if req.typ == ResetType::Hard {
+                    dev.indicate(&sys::Reset {
+                        reason: sys::ResetReason::Watchdog,
+                        transport_rev: 2,
+                        product_id: 1,
+                        major_rel: 2,
+                        minor_rel: 7,
+                        hw_rev: 1,
+                    });
+                }
+            });
+        let (io, _handle) = sim.spawn().unwrap();
+        let opts = Options {
+            watchdog: Some(Watchdog {
+                interval: Duration::from_millis(10),
+                max_timeouts: 1,
+                reset_timeout: Duration::from_millis(200),
+            }),
+            ..Default::default()
+        };
+        let znp = Sender::with_options(io, opts);
+        let mut status = znp.status();
+        let events = async {
+            let mut events = Vec::new();
+            while let Ok(status) = status.recv().await {
+                if let Status::Watchdog(event) = status {
+                    let restarting = matches!(event, WatchdogEvent::Restarting);
+                    events.push(event);
+                    if restarting {
+                        return events;
+                    }
+                }
+            }
+            panic!("status channel closed");
+        };
+        let events = timeout(Duration::from_secs(5), events).await.unwrap();
+        assert!(
+            matches!(
+                events[..],
+                [
+                    WatchdogEvent::Unresponsive(1),
+                    WatchdogEvent::Resetting(ResetType::Soft),
+                    WatchdogEvent::Resetting(ResetType::Hard),
+                    WatchdogEvent::Restarting,
+                ]
+            ),
+            "{:?}",
+            events
+        );
+    }
+
+    #[tokio::test]
+    async fn startup_reruns_on_unexpected_reset_only() {
+        use std::sync::atomic::AtomicUsize;
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = runs.clone();
+        let (io, handle) = Simulator::new().spawn().unwrap();
+        let opts = Options {
+            startup: Some(startup(move |_| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                future::ready(Ok::<_, ()>(()))
+            })),
+            ..Default::default()
+        };
+        let znp = Sender::with_options(io, opts);
+        let mut status = znp.status();
+        // Requests wait for startup, so once one is answered the count is settled
+        znp.sreq(sys::Ping).await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        let reset = sys::ResetReq {
+            typ: sys::ResetType::Soft,
+        };
+        znp.areq(reset).await.unwrap();
+        wait_for(&mut status, |status| matches!(status, Status::Reset(_))).await;
+        znp.sreq(sys::Ping).await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // Like a firmware crash
+        handle
+            .inject(&sys::Reset {
+                reason: sys::ResetReason::Watchdog,
+                transport_rev: 2,
+                product_id: 1,
+                major_rel: 2,
+                minor_rel: 7,
+                hw_rev: 1,
+            })
+            .unwrap();
+        wait_for(&mut status, |status| {
+            matches!(status, Status::UnexpectedReset(_))
+        })
+        .await;
+        wait_for(&mut status, |status| matches!(status, Status::Ready)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn sreq_callback_unregisters_on_errors() {
+        let (io, _handle) = Simulator::new().spawn().unwrap();
+        let znp = Sender::new(io);
+        // Accepted, but nobody answers for a device that isn't there
+        let req = cmd::zdo::NodeDescReq {
+            dest_addr: cmd::types::ShortAddr(0x1234),
+            query_addr: cmd::types::ShortAddr(0x1234),
+        };
+        let opts = SreqOptions {
+            callback_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        match znp.sreq_callback_with(req, opts).await {
+            Err(SreqError::NoCallback) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(znp.waiters.lock().unwrap().is_empty());
+
+        let (io, _handle) = Simulator::empty().spawn().unwrap();
+        let znp = Sender::new(io);
+        let req = cmd::zdo::NodeDescReq {
+            dest_addr: cmd::types::ShortAddr(0x0000),
+            query_addr: cmd::types::ShortAddr(0x0000),
+        };
+        match znp.sreq_callback(req).await {
+            Err(SreqError::Rpc(_)) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(znp.waiters.lock().unwrap().is_empty());
     }
 }
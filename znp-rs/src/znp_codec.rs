@@ -1,8 +1,11 @@
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{buf::BufMutExt, Buf, BufMut, BytesMut};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio_util::codec::{Decoder, Encoder};
 
 const SOF: u8 = 0xFE;
@@ -11,7 +14,7 @@ fn xor(buf: &[u8]) -> u8 {
     buf.iter().fold(0x00, |acc, x| acc ^ x)
 }
 
-#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq)]
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Serialize, Deserialize)]
 pub enum Type {
     POLL = 0x00,
     SREQ = 0x20,
@@ -19,7 +22,7 @@ pub enum Type {
     SRSP = 0x60,
 }
 
-#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq)]
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Subsys {
     Reserved = 0x00,
     SYS = 0x01,
@@ -32,7 +35,7 @@ pub enum Subsys {
     DEBUG = 0x08,
     APP = 0x09,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ZnpCmd {
     typ: Type,
     subsys: Subsys,
@@ -57,61 +60,136 @@ impl ZnpCmd {
     pub fn cmd_id(&self) -> u8 {
         self.cmd_id
     }
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
     pub fn parse<T: DeserializeOwned>(&self) -> crate::serde_znp::Result<T> {
         crate::serde_znp::deserialize(&self.body)
     }
+    /// An RPC_Error SRSP, which the coordinator sends instead of the real SRSP
+    /// to an SREQ it can't handle. Returns the error code, and Cmd0 and Cmd1 of that SREQ.
+    pub fn rpc_error(&self) -> Option<(u8, u8, u8)> {
+        match (self.typ, self.subsys, self.cmd_id, &self.body[..]) {
+            (Type::SRSP, Subsys::Reserved, 0x00, &[status, cmd0, cmd1]) => {
+                Some((status, cmd0, cmd1))
+            }
+            _ => None,
+        }
+    }
+    /// Inverse of `parse`
+    pub fn from_payload<T: Serialize>(
+        typ: Type,
+        subsys: Subsys,
+        cmd_id: u8,
+        payload: &T,
+    ) -> crate::serde_znp::Result<Self> {
+        let mut body = BytesMut::new();
+        crate::serde_znp::serialize((&mut body).writer(), payload)?;
+        Ok(ZnpCmd::new(typ, subsys, cmd_id, body))
+    }
+}
+/// Counters of input the decoder threw away while resynchronizing
+#[derive(Debug, Default)]
+pub struct CodecStats {
+    dropped_bytes: AtomicUsize,
+    bad_fcs: AtomicUsize,
+}
+impl CodecStats {
+    /// Bytes discarded while scanning for the next SOF, including ones from corrupt frames
+    pub fn dropped_bytes(&self) -> usize {
+        self.dropped_bytes.load(Ordering::Relaxed)
+    }
+    /// Frames discarded because of an FCS mismatch
+    pub fn bad_fcs(&self) -> usize {
+        self.bad_fcs.load(Ordering::Relaxed)
+    }
+    fn drop_bytes(&self, count: usize) {
+        self.dropped_bytes.fetch_add(count, Ordering::Relaxed);
+    }
+}
+#[derive(Default)]
+pub struct ZnpCodec {
+    stats: Arc<CodecStats>,
+}
+impl ZnpCodec {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Keep counting into existing counters, e.g. across reconnects
+    pub fn with_stats(stats: Arc<CodecStats>) -> Self {
+        ZnpCodec { stats }
+    }
+    /// Shared handle to the counters, still readable after the codec is moved into `Framed`
+    pub fn stats(&self) -> Arc<CodecStats> {
+        self.stats.clone()
+    }
 }
-pub struct ZnpCodec;
 impl Decoder for ZnpCodec {
     type Item = ZnpCmd;
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let len = buf.len();
-        // Minimum frame is:
-        // SOF + Length + Cmd0 + Cmd1 + FCS
-        // [0xFE, 0x00, 0xXX, 0xXX, 0xXX]
-        if len < 5 {
-            return Ok(None);
-        }
-        if buf[0] != SOF {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Desync: SOF byte not 0xFE",
-            ));
-        }
-        let length = buf[1] as usize;
-        let frame_len = 5 + length;
-        if len < frame_len {
-            return Ok(None);
+        loop {
+            // Scan for SOF, discarding line noise and bootloader output
+            match buf.iter().position(|&b| b == SOF) {
+                Some(0) => {}
+                Some(skip) => {
+                    self.stats.drop_bytes(skip);
+                    buf.advance(skip);
+                }
+                None => {
+                    self.stats.drop_bytes(buf.len());
+                    buf.clear();
+                    return Ok(None);
+                }
+            }
+            // Minimum frame is:
+            // SOF + Length + Cmd0 + Cmd1 + FCS
+            // [0xFE, 0x00, 0xXX, 0xXX, 0xXX]
+            if buf.len() < 5 {
+                return Ok(None);
+            }
+            let length = buf[1] as usize;
+            if length > 250 {
+                // Not a real frame, this 0xFE was just data
+                self.stats.drop_bytes(1);
+                buf.advance(1);
+                continue;
+            }
+            let frame_len = 5 + length;
+            if buf.len() < frame_len {
+                return Ok(None);
+            }
+            // XORing Length + Cmd0 + Cmd1 + Body{Length} + FCS
+            if xor(&buf[1..frame_len]) != 0 {
+                // Only drop the SOF, a real frame may start inside this one
+                self.stats.bad_fcs.fetch_add(1, Ordering::Relaxed);
+                self.stats.drop_bytes(1);
+                buf.advance(1);
+                continue;
+            }
+            let mut frame = buf.split_to(frame_len);
+            let cmd0 = frame[2];
+            let (typ, subsys) = match (Type::from_u8(cmd0 & 0xf0), Subsys::from_u8(cmd0 & 0xf)) {
+                (Some(typ), Some(subsys)) => (typ, subsys),
+                _ => {
+                    // Valid checksum, but not something we can represent
+                    self.stats.drop_bytes(frame_len);
+                    continue;
+                }
+            };
+            let cmd_id = frame[3];
+            // Skip: SOF + Length + Cmd0 + Cmd1
+            frame.advance(4);
+            // Drop: FCS
+            frame.truncate(frame.len() - 1);
+            return Ok(Some(ZnpCmd {
+                typ,
+                subsys,
+                cmd_id,
+                body: frame,
+            }));
         }
-        let mut frame = buf.split_to(frame_len);
-        // Skip: SOF
-        frame.advance(1);
-        // Remaining: Length + Cmd0 + Cmd1 + Body{Length} + FCS
-        if xor(&frame) != 0 {
-            // XORing including the FCS
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Desync: FCS mismatch",
-            ));
-        }
-        // Drop: FCS
-        frame.truncate(frame.len() - 1);
-        let cmd0 = frame[1];
-        let typ = Type::from_u8(cmd0 & 0xf0)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown Type"))?;
-        let subsys = Subsys::from_u8(cmd0 & 0xf)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown Subsystem"))?;
-        let cmd_id = frame[2];
-        // Skip: Length + Cmd0 + Cmd1
-        frame.advance(3);
-        Ok(Some(ZnpCmd {
-            typ,
-            subsys,
-            cmd_id,
-            body: frame,
-        }))
     }
 }
 impl Encoder for ZnpCodec {
@@ -144,3 +222,112 @@ impl Encoder for ZnpCodec {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SYS command 0x01 with `body`, as it goes over the wire
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let cmd = ZnpCmd::new(Type::AREQ, Subsys::SYS, 0x01, BytesMut::from(body));
+        let mut buf = BytesMut::new();
+        ZnpCodec::new().encode(cmd, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    fn decode_all(codec: &mut ZnpCodec, input: &[u8]) -> Vec<ZnpCmd> {
+        let mut buf = BytesMut::from(input);
+        let mut cmds = vec![];
+        while let Some(cmd) = codec.decode(&mut buf).unwrap() {
+            cmds.push(cmd);
+        }
+        cmds
+    }
+
+    fn assert_body(cmd: &ZnpCmd, body: &[u8]) {
+        assert_eq!(
+            (cmd.typ(), cmd.subsys(), cmd.cmd_id()),
+            (Type::AREQ, Subsys::SYS, 0x01)
+        );
+        assert_eq!(cmd.body(), body);
+    }
+
+    #[test]
+    fn drops_leading_garbage() {
+        let mut codec = ZnpCodec::new();
+        let mut input = vec![0x00, 0x11, 0x22];
+        input.extend(frame(&[0xAA, 0xBB]));
+        let cmds = decode_all(&mut codec, &input);
+        assert_eq!(cmds.len(), 1);
+        assert_body(&cmds[0], &[0xAA, 0xBB]);
+        assert_eq!(codec.stats().dropped_bytes(), 3);
+        assert_eq!(codec.stats().bad_fcs(), 0);
+    }
+
+    #[test]
+    fn resyncs_after_bad_fcs() {
+        let mut codec = ZnpCodec::new();
+        let mut input = frame(&[0x01, 0x02]);
+        *input.last_mut().unwrap() ^= 0xFF;
+        input.extend(frame(&[0xAA, 0xBB]));
+        let cmds = decode_all(&mut codec, &input);
+        assert_eq!(cmds.len(), 1);
+        assert_body(&cmds[0], &[0xAA, 0xBB]);
+        // The whole corrupt frame, SOF first
+        assert_eq!(codec.stats().dropped_bytes(), 7);
+        assert_eq!(codec.stats().bad_fcs(), 1);
+    }
+
+    #[test]
+    fn sof_in_body() {
+        let mut codec = ZnpCodec::new();
+        let cmds = decode_all(&mut codec, &frame(&[SOF, 0x01, SOF]));
+        assert_eq!(cmds.len(), 1);
+        assert_body(&cmds[0], &[SOF, 0x01, SOF]);
+        assert_eq!(codec.stats().dropped_bytes(), 0);
+        assert_eq!(codec.stats().bad_fcs(), 0);
+    }
+
+    #[test]
+    fn finds_frame_inside_corrupt_one() {
+        let mut codec = ZnpCodec::new();
+        let inner = frame(&[0xAA, 0xBB]);
+        let mut outer = frame(&inner);
+        *outer.last_mut().unwrap() ^= 0xFF;
+        let cmds = decode_all(&mut codec, &outer);
+        assert_eq!(cmds.len(), 1);
+        assert_body(&cmds[0], &[0xAA, 0xBB]);
+        // Outer SOF, length, Cmd0, Cmd1, then its FCS once nothing follows
+        assert_eq!(codec.stats().dropped_bytes(), 5);
+        assert_eq!(codec.stats().bad_fcs(), 1);
+    }
+
+    #[test]
+    fn skips_length_over_250() {
+        let mut codec = ZnpCodec::new();
+        let mut input = vec![SOF, 251, 0x21, 0x01];
+        input.extend(frame(&[0xAA]));
+        let cmds = decode_all(&mut codec, &input);
+        assert_eq!(cmds.len(), 1);
+        assert_body(&cmds[0], &[0xAA]);
+        assert_eq!(codec.stats().dropped_bytes(), 4);
+        assert_eq!(codec.stats().bad_fcs(), 0);
+    }
+
+    #[test]
+    fn waits_for_split_frame() {
+        let input = frame(&[0xAA, 0xBB, 0xCC]);
+        for split in 1..input.len() {
+            let mut codec = ZnpCodec::new();
+            let mut buf = BytesMut::from(&input[..split]);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+            assert_eq!(buf.len(), split, "partial frame consumed");
+            buf.extend_from_slice(&input[split..]);
+            let cmd = codec.decode(&mut buf).unwrap().expect("frame");
+            assert_body(&cmd, &[0xAA, 0xBB, 0xCC]);
+            assert!(buf.is_empty());
+            assert_eq!(codec.stats().dropped_bytes(), 0);
+            assert_eq!(codec.stats().bad_fcs(), 0);
+        }
+    }
+}